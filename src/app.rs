@@ -1,20 +1,62 @@
+use std::collections::HashMap;
+
+use chrono::{NaiveDate, Utc};
+use color_eyre::Result;
 use ratatui::widgets::ListState;
 use tui_textarea::TextArea;
 
 use crate::{
-    entities::{ParentTask, Task, TaskData},
-    history::{AppHistory, AppSnapshot},
+    dates::{self, AgendaGroup},
+    entities::{self, DateKind, ParentTask, Subtree, Task, TaskData, TaskId, TaskStatus},
+    history::{AppHistory, AppSnapshot, TaskChanges},
     storage::AppStorage,
 };
 
 pub enum AppState {
     Normal,
     EditTask {
-        task_id: u64,
+        task_id: TaskId,
     },
     InsertTask {
         parent: ParentTask,
         position: Option<usize>,
+        /// Open the new task right after inserting it.
+        descend: bool,
+    },
+    /// Editing the notes of a task in a full-size pane, where Enter adds a line.
+    EditNotes {
+        task_id: TaskId,
+    },
+    /// Typing a due or scheduled date for a task, in the title popup.
+    EditDate {
+        task_id: TaskId,
+        kind: DateKind,
+    },
+    /// Picking the status of a task from the list of statuses.
+    PickStatus {
+        task_id: TaskId,
+    },
+    /// Browsing the tags, then the tasks carrying the chosen one across the whole tree.
+    Tags {
+        /// `None` while choosing a tag.
+        tag: Option<String>,
+        /// Position in the tags, or in the tasks carrying `tag`.
+        selected: usize,
+    },
+    /// Browsing every open task with a date.
+    Agenda {
+        /// Position in `App::agenda`.
+        selected: usize,
+    },
+    /// Browsing the undo tree.
+    History {
+        /// `AppHistory::nodes_in_order` as of when it was opened, the history can't change while
+        /// it's shown.
+        states: Vec<(usize, usize)>,
+        /// Position in `states`.
+        selected: usize,
+        /// The changes going to the selected state would make, `None` for the current one.
+        preview: Option<TaskChanges>,
     },
 }
 
@@ -23,8 +65,16 @@ pub struct App<'a> {
 
     pub history: AppHistory,
 
+    /// Last deleted or yanked subtree, ready to be pasted.
+    pub register: Option<Subtree>,
+
     pub state: AppState,
     pub text_area: TextArea<'a>,
+    /// Position of the highlighted one in `tag_suggestions`.
+    pub tag_suggestion: usize,
+
+    /// Shown at the bottom until the next key press.
+    pub status_message: Option<String>,
 }
 
 impl App<'_> {
@@ -32,11 +82,19 @@ impl App<'_> {
         let mut elements_list = ListState::default();
         elements_list.select(Some(0));
 
+        let history = match storage.history_file_path() {
+            Some(history_path) => AppHistory::load(&history_path, &storage.tasks),
+            None => AppHistory::new(&storage.tasks),
+        };
+
         Self {
             storage,
-            history: AppHistory::default(),
+            history,
+            register: None,
             state: AppState::Normal,
             text_area: TextArea::default(),
+            tag_suggestion: 0,
+            status_message: None,
         }
     }
 
@@ -71,21 +129,67 @@ impl App<'_> {
         self.storage.find_parents_stack()
     }
 
-    pub fn delete_selected_task(&mut self) -> Option<u64> {
-        self.save_snapshot();
-
+    pub fn delete_selected_task(&mut self) -> Option<TaskId> {
         let current_position = self.storage.get_selected_position()?;
 
         let id_to_delete = self.get_selected_task()?.id;
-        let removed_task = self.storage.remove_task(id_to_delete)?;
+        self.save_snapshot(self.describe_subtree("deleted", id_to_delete)?);
+
+        let removed_subtree = self.storage.remove_task(id_to_delete)?;
+        let removed_id = removed_subtree.root;
+        self.register = Some(removed_subtree);
 
         self.move_selection_to(current_position.into());
-        Some(removed_task.id)
+        Some(removed_id)
+    }
+
+    pub fn yank_selected_task(&mut self) -> Option<()> {
+        let selected_id = self.get_selected_task()?.id;
+        self.register = Some(self.storage.copy_subtree(selected_id)?);
+        Some(())
+    }
+
+    pub fn paste_above(&mut self) -> Option<()> {
+        let position = self.storage.get_selected_position().unwrap_or(0);
+        self.paste_at(position)
+    }
+
+    pub fn paste_below(&mut self) -> Option<()> {
+        let position = match self.get_selected_task() {
+            Some(_) => self.storage.get_selected_position().unwrap_or(0) + 1,
+            None => 0,
+        };
+        self.paste_at(position)
+    }
+
+    fn paste_at(&mut self, position: usize) -> Option<()> {
+        let subtree = self.register.clone()?;
+        let parent = self.storage.get_opened_task();
+        let label = describe_subtree("pasted", &subtree)?;
+
+        let snapshot = self.create_snapshot();
+        self.storage.insert_subtree(parent, subtree, position.into())?;
+        self.history.save_snapshot(snapshot, label, &self.storage.tasks);
+        self.move_selection_to(position.into());
+        Some(())
+    }
+
+    /// Pastes the register as the last child of the selected task, then opens the task with the
+    /// pasted one selected.
+    pub fn paste_as_child(&mut self) -> Option<()> {
+        let subtree = self.register.clone()?;
+        let parent = ParentTask::Id(self.get_selected_task()?.id);
+        let label = describe_subtree("pasted", &subtree)?;
+
+        let snapshot = self.create_snapshot();
+        let pasted_id = self.storage.insert_subtree(parent, subtree, None)?;
+        self.history.save_snapshot(snapshot, label, &self.storage.tasks);
+        self.storage.select_task(pasted_id)
     }
 
     pub fn move_selection_to(&mut self, index: Option<usize>) {
         let max_index = self.find_opened_sub_tasks().len().saturating_sub(1);
-        let new_index = index.unwrap_or(0).max(0).min(max_index);
+        let new_index = index.unwrap_or(0).min(max_index);
         self.storage.set_selected_position(new_index);
     }
 
@@ -119,7 +223,8 @@ impl App<'_> {
         let to_id = tasks.get(to_index)?.id;
 
         if from_id != to_id {
-            self.save_snapshot();
+            let label = format!("moved '{}' up", tasks[from_index].title);
+            self.save_snapshot(label);
             self.storage.swap_current_sub_tasks(from_id, to_id);
             self.move_selection_up();
         }
@@ -139,25 +244,87 @@ impl App<'_> {
         let to_id = tasks.get(to_index)?.id;
 
         if from_id != to_id {
-            self.save_snapshot();
+            let label = format!("moved '{}' down", tasks[from_index].title);
+            self.save_snapshot(label);
             self.storage.swap_current_sub_tasks(from_id, to_id);
             self.move_selection_down();
         }
         Some(())
     }
 
+    pub fn indent_selected_task(&mut self) -> Option<()> {
+        let selected_task = self.get_selected_task()?;
+        let selected_id = selected_task.id;
+        let label = format!("indented '{}'", selected_task.title);
+
+        let snapshot = self.create_snapshot();
+        self.storage.indent_task(selected_id)?;
+        self.history.save_snapshot(snapshot, label, &self.storage.tasks);
+
+        self.storage.select_task(selected_id)
+    }
+
+    pub fn outdent_selected_task(&mut self) -> Option<()> {
+        let selected_task = self.get_selected_task()?;
+        let selected_id = selected_task.id;
+        let label = format!("outdented '{}'", selected_task.title);
+
+        let snapshot = self.create_snapshot();
+        self.storage.outdent_task(selected_id)?;
+        self.history.save_snapshot(snapshot, label, &self.storage.tasks);
+
+        self.storage.select_task(selected_id)
+    }
+
     pub fn open_selected_task(&mut self) {
         if let Some(task) = self.get_selected_task() {
             let new_parent = ParentTask::Id(task.id);
-            self.storage.view.set_opened_task(new_parent);
+            self.storage.set_opened_task(new_parent);
         }
     }
 
+    /// Marks the selected task done, or back to do if it's closed.
     pub fn update_done_state(&mut self) -> Option<()> {
-        self.save_snapshot();
         let selected_task = self.get_selected_task()?;
-        let new_done_state = !selected_task.done;
-        self.storage.update_task_state(selected_task.id, new_done_state);
+        let status = match selected_task.status.is_closed() {
+            true => TaskStatus::Todo,
+            false => TaskStatus::Done,
+        };
+        self.set_task_status(selected_task.id, status)
+    }
+
+    /// Moves the selected task to the next status of `TaskStatus::ALL`.
+    pub fn cycle_selected_task_status(&mut self) -> Option<()> {
+        let selected_task = self.get_selected_task()?;
+        self.set_task_status(selected_task.id, selected_task.status.next())
+    }
+
+    pub fn open_status_picker(&mut self) -> Option<()> {
+        let task_id = self.get_selected_task()?.id;
+        self.state = AppState::PickStatus { task_id };
+        Some(())
+    }
+
+    /// Leaves the status picker, giving the task `status` unless it's `None`.
+    pub fn close_status_picker(&mut self, status: Option<TaskStatus>) {
+        let AppState::PickStatus { task_id } = self.state else {
+            return;
+        };
+        self.state = AppState::Normal;
+
+        if let Some(status) = status {
+            self.set_task_status(task_id, status);
+        }
+    }
+
+    fn set_task_status(&mut self, task_id: TaskId, status: TaskStatus) -> Option<()> {
+        let task = self.storage.get_task(task_id)?;
+        if task.status == status {
+            return None;
+        }
+
+        self.save_snapshot(format!("marked '{}' {}", task.title, status.label()));
+        self.storage.update_task_status(task_id, status);
         Some(())
     }
 
@@ -173,13 +340,50 @@ impl App<'_> {
         Some(())
     }
 
+    /// Marks the opened task as done and unwinds to its parent, selecting the next pending sibling.
+    ///
+    /// Unless `force` is set, does nothing while the opened task still has open children, blocked
+    /// ones included, cancelled children don't hold it back.
+    pub fn pop_opened_task(&mut self, force: bool) -> Option<()> {
+        let ParentTask::Id(opened_task_id) = self.storage.get_opened_task() else {
+            return None;
+        };
+
+        if !force && self.find_opened_sub_tasks().iter().any(|task| !task.status.is_closed()) {
+            return None;
+        }
+
+        let label = format!("popped '{}'", self.storage.get_task(opened_task_id)?.title);
+        self.save_snapshot(label);
+        self.storage.update_task_status(opened_task_id, TaskStatus::Done);
+        self.get_back_to_parent()?;
+
+        let siblings = self.find_opened_sub_tasks();
+        let popped_position = siblings.iter().position(|task| task.id == opened_task_id)?;
+        let next_position = (popped_position + 1..siblings.len())
+            .chain(0..popped_position)
+            .find(|&position| !siblings[position].status.is_closed())
+            .unwrap_or(popped_position);
+
+        self.move_selection_to(next_position.into());
+        Some(())
+    }
+
+    pub fn jump_back(&mut self) -> Option<()> {
+        self.storage.jump_back()
+    }
+
+    pub fn jump_forward(&mut self) -> Option<()> {
+        self.storage.jump_forward()
+    }
+
     pub fn init_insert_mode_to_insert_new_task_above(&mut self) -> Option<()> {
         let position = self.storage.get_selected_position();
         self.init_insert_mode_to_insert_new_task(position)
     }
 
     pub fn init_insert_mode_to_insert_new_task_below(&mut self) -> Option<()> {
-        let position = self.storage.get_selected_position().map(|p| (p + 1));
+        let position = self.storage.get_selected_position().map(|p| p + 1);
         self.init_insert_mode_to_insert_new_task(position)
     }
 
@@ -190,7 +394,23 @@ impl App<'_> {
 
     pub fn init_insert_mode_to_insert_new_task(&mut self, position: Option<usize>) -> Option<()> {
         let parent = self.storage.get_opened_task();
-        self.state = AppState::InsertTask { parent, position };
+        self.state = AppState::InsertTask {
+            parent,
+            position,
+            descend: false,
+        };
+        self.text_area = TextArea::default();
+        Some(())
+    }
+
+    /// Inserts a task at the end of the opened task and opens it, pushing it to the stack.
+    pub fn init_insert_mode_to_push_new_task(&mut self) -> Option<()> {
+        let parent = self.storage.get_opened_task();
+        self.state = AppState::InsertTask {
+            parent,
+            position: None,
+            descend: true,
+        };
         self.text_area = TextArea::default();
         Some(())
     }
@@ -207,6 +427,131 @@ impl App<'_> {
         Some(())
     }
 
+    pub fn init_date_editor(&mut self, kind: DateKind) -> Option<()> {
+        let selected_task = self.get_selected_task()?;
+
+        let task_id = selected_task.id;
+        let date = selected_task.date(kind);
+        self.text_area = TextArea::from(date.map(|date| date.to_string()));
+        self.text_area.move_cursor(tui_textarea::CursorMove::End);
+        self.state = AppState::EditDate { task_id, kind };
+        Some(())
+    }
+
+    /// Leaves the date popup, setting the date typed, or clearing it if nothing was typed.
+    pub fn close_date_editor(&mut self) {
+        let AppState::EditDate { task_id, kind } = self.state else {
+            return;
+        };
+        self.state = AppState::Normal;
+
+        let input = self.text_area.lines().join(" ");
+        let date = match input.trim() {
+            "" => None,
+            input => match dates::parse_date(input, dates::today()) {
+                Some(date) => Some(date),
+                None => {
+                    self.status_message = Some(format!(
+                        "Couldn't read '{input}' as a date, try e.g. fri, tomorrow, +3d or 2026-11-02"
+                    ));
+                    return;
+                }
+            },
+        };
+
+        let Some(task) = self.storage.get_task(task_id) else {
+            return;
+        };
+        if task.date(kind) == date {
+            return;
+        }
+        let label = match date {
+            Some(date) => format!("set the {} of '{}' to {date}", kind.label(), task.title),
+            None => format!("cleared the {} of '{}'", kind.label(), task.title),
+        };
+
+        self.save_snapshot(label);
+        self.storage.update_task_date(task_id, kind, date);
+    }
+
+    /// Every open task with a date, grouped by when it comes up, then by date and order in the
+    /// tree.
+    pub fn agenda(&self) -> Vec<(AgendaGroup, NaiveDate, &Task)> {
+        let today = dates::today();
+        let mut agenda: Vec<_> = self
+            .storage
+            .find_all_tasks()
+            .into_iter()
+            .filter_map(|task| dates::agenda_group(task, today).map(|(group, date)| (group, date, task)))
+            .collect();
+        // Stable, so tasks on the same date keep the order of the tree
+        agenda.sort_by_key(|&(group, date, _)| (group, date));
+        agenda
+    }
+
+    pub fn open_agenda(&mut self) {
+        self.state = AppState::Agenda { selected: 0 };
+    }
+
+    pub fn close_agenda(&mut self) {
+        self.state = AppState::Normal;
+    }
+
+    /// Moves the selection in the agenda by `offset` tasks, staying within it.
+    pub fn move_agenda_selection(&mut self, offset: isize) {
+        let max_selected = self.agenda().len().saturating_sub(1);
+        if let AppState::Agenda { selected } = &mut self.state {
+            *selected = selected.saturating_add_signed(offset).min(max_selected);
+        }
+    }
+
+    /// Closes the agenda and goes to the selected task.
+    pub fn jump_to_selected_agenda_task(&mut self) -> Option<()> {
+        let AppState::Agenda { selected } = self.state else {
+            return None;
+        };
+
+        let (_, _, task) = self.agenda().get(selected).copied()?;
+        let task_id = task.id;
+        self.state = AppState::Normal;
+        self.storage.select_task(task_id)
+    }
+
+    pub fn init_notes_editor(&mut self) -> Option<()> {
+        let selected_task = self.get_selected_task()?;
+
+        let task_id = selected_task.id;
+        self.text_area = TextArea::from(selected_task.notes.lines());
+        self.text_area.move_cursor(tui_textarea::CursorMove::Bottom);
+        self.text_area.move_cursor(tui_textarea::CursorMove::End);
+        self.state = AppState::EditNotes { task_id };
+        Some(())
+    }
+
+    /// Leaves the notes editor, saving the notes if they changed unless `discard` is set.
+    pub fn close_notes_editor(&mut self, discard: bool) {
+        let AppState::EditNotes { task_id } = self.state else {
+            return;
+        };
+        self.state = AppState::Normal;
+
+        let notes = self.text_area.lines().join("\n").trim_end().to_owned();
+        let Some(task) = self
+            .storage
+            .get_task(task_id)
+            .filter(|task| !discard && task.notes != notes)
+        else {
+            return;
+        };
+        let label = match notes.is_empty() {
+            true => format!("cleared the notes of '{}'", task.title),
+            false => format!("edited the notes of '{}'", task.title),
+        };
+
+        self.save_snapshot(label);
+        self.storage.update_task_notes(task_id, notes);
+    }
+
     pub fn cancel_insert_mode(&mut self) {
         self.state = AppState::Normal;
     }
@@ -220,13 +565,23 @@ impl App<'_> {
                 return;
             }
 
-            self.save_snapshot();
+            let Some(task) = self.storage.get_task(task_id) else {
+                return;
+            };
+            let label = format!("renamed '{}' to '{content}'", task.title);
+
+            self.save_snapshot(label);
             self.storage.update_task_title(task_id, content);
         }
     }
 
     pub fn close_insert_mode_inserting_new_task(&mut self) {
-        if let AppState::InsertTask { parent, position } = self.state {
+        if let AppState::InsertTask {
+            parent,
+            position,
+            descend,
+        } = self.state
+        {
             self.state = AppState::Normal;
             let content = self.text_area.lines().join("\n");
 
@@ -234,59 +589,289 @@ impl App<'_> {
                 return;
             }
 
-            self.save_snapshot();
-
+            let label = format!("added '{content}'");
             let task_data = TaskData {
                 title: content,
                 children: vec![],
-                done: false,
+                status: TaskStatus::Todo,
             };
 
+            // A reload while the popup was open may have removed the parent or some of its children
+            let snapshot = self.create_snapshot();
+            let inserted = match position {
+                Some(position) => self.storage.insert_task_at(parent, task_data, position),
+                None => self.storage.insert_task(parent, task_data),
+            };
+            let Some(new_task_id) = inserted else {
+                self.status_message =
+                    Some("The tasks changed outside of nest while typing, the new task wasn't added".to_owned());
+                return;
+            };
+            self.history.save_snapshot(snapshot, label, &self.storage.tasks);
+
             match position {
-                Some(position) => {
-                    self.storage
-                        .insert_task_at(parent, task_data, position)
-                        .expect("Position out of bounds");
-                    self.move_selection_to(position.into());
-                }
-                None => {
-                    self.storage.insert_task(parent, task_data);
-                    self.move_selection_to_bottom();
-                }
+                Some(position) => self.move_selection_to(position.into()),
+                None => self.move_selection_to_bottom(),
+            }
+
+            if descend {
+                self.storage.set_opened_task(ParentTask::Id(new_task_id));
             }
         }
     }
 
-    pub fn save_snapshot(&mut self) {
+    /// Every tag used in a title with how many tasks carry it, the most used first.
+    pub fn find_tags(&self) -> Vec<(String, usize)> {
+        let mut counts = HashMap::<&str, usize>::new();
+        for task in self.storage.tasks.values() {
+            for tag in task.tags() {
+                *counts.entry(tag).or_default() += 1;
+            }
+        }
+
+        let mut tags: Vec<_> = counts.into_iter().map(|(tag, count)| (tag.to_owned(), count)).collect();
+        tags.sort_by(|(tag_a, count_a), (tag_b, count_b)| count_b.cmp(count_a).then_with(|| tag_a.cmp(tag_b)));
+        tags
+    }
+
+    /// Every task carrying `tag`, in the order they're shown.
+    pub fn find_tagged_tasks(&self, tag: &str) -> Vec<&Task> {
+        self.storage
+            .find_all_tasks()
+            .into_iter()
+            .filter(|task| task.tags().any(|task_tag| task_tag == tag))
+            .collect()
+    }
+
+    /// The start of the tag right before the cursor in the title popup, if one is being typed.
+    fn typed_tag(&self) -> Option<&str> {
+        let (row, column) = self.text_area.cursor();
+        let line = self.text_area.lines().get(row)?;
+        let before_cursor = &line[..line.char_indices().nth(column).map_or(line.len(), |(index, _)| index)];
+        let word = before_cursor.rsplit(char::is_whitespace).next()?;
+        word.starts_with(entities::TAG_SIGILS).then_some(word)
+    }
+
+    /// Tags already in use completing the one being typed in the title popup, at most 5.
+    pub fn tag_suggestions(&self) -> Vec<String> {
+        let (AppState::InsertTask { .. } | AppState::EditTask { .. }) = self.state else {
+            return vec![];
+        };
+        let Some(typed_tag) = self.typed_tag() else {
+            return vec![];
+        };
+
+        self.find_tags()
+            .into_iter()
+            .map(|(tag, _)| tag)
+            .filter(|tag| tag.starts_with(typed_tag) && tag != typed_tag)
+            .take(5)
+            .collect()
+    }
+
+    pub fn move_tag_suggestion(&mut self, offset: isize) {
+        let max_suggestion = self.tag_suggestions().len().saturating_sub(1);
+        self.tag_suggestion = self.tag_suggestion.saturating_add_signed(offset).min(max_suggestion);
+    }
+
+    /// Replaces the tag being typed with the highlighted suggestion.
+    pub fn complete_tag(&mut self) -> Option<()> {
+        let suggestions = self.tag_suggestions();
+        let suggestion = suggestions.get(self.tag_suggestion).or(suggestions.first())?;
+        let typed_len = self.typed_tag()?.chars().count();
+
+        for _ in 0..typed_len {
+            self.text_area.delete_char();
+        }
+        self.text_area.insert_str(format!("{suggestion} "));
+        self.tag_suggestion = 0;
+        Some(())
+    }
+
+    /// Opens the list of tags, on the first tag of the selected task if it has one.
+    pub fn open_tags(&mut self) {
+        let tags = self.find_tags();
+        let selected = self
+            .get_selected_task()
+            .and_then(|task| task.tags().next())
+            .and_then(|task_tag| tags.iter().position(|(tag, _)| tag == task_tag))
+            .unwrap_or(0);
+        self.state = AppState::Tags { tag: None, selected };
+    }
+
+    /// Goes from the tasks of a tag back to the list of tags, or closes the list.
+    pub fn close_tags(&mut self) {
+        let AppState::Tags { tag: Some(tag), .. } = &self.state else {
+            self.state = AppState::Normal;
+            return;
+        };
+
+        let tags = self.find_tags();
+        let selected = tags.iter().position(|(other_tag, _)| other_tag == tag).unwrap_or(0);
+        self.state = AppState::Tags { tag: None, selected };
+    }
+
+    /// Moves the selection in the tags or their tasks by `offset`, staying within them.
+    pub fn move_tags_selection(&mut self, offset: isize) {
+        let len = match &self.state {
+            AppState::Tags { tag: None, .. } => self.find_tags().len(),
+            AppState::Tags { tag: Some(tag), .. } => self.find_tagged_tasks(tag).len(),
+            _ => return,
+        };
+        if let AppState::Tags { selected, .. } = &mut self.state {
+            *selected = selected.saturating_add_signed(offset).min(len.saturating_sub(1));
+        }
+    }
+
+    /// Lists the tasks of the selected tag, or goes to the selected task.
+    pub fn open_selected_tag_item(&mut self) -> Option<()> {
+        let AppState::Tags { tag, selected } = &self.state else {
+            return None;
+        };
+
+        match tag {
+            None => {
+                let (tag, _) = self.find_tags().into_iter().nth(*selected)?;
+                self.state = AppState::Tags {
+                    tag: Some(tag),
+                    selected: 0,
+                };
+            }
+            Some(tag) => {
+                let task_id = self.find_tagged_tasks(tag).get(*selected)?.id;
+                self.state = AppState::Normal;
+                self.storage.select_task(task_id)?;
+            }
+        }
+        Some(())
+    }
+
+    /// Takes a snapshot before a change, `label` describing the change in the history.
+    pub fn save_snapshot(&mut self, label: String) {
         let snapshot = self.create_snapshot();
-        self.history.save_snapshot(snapshot);
+        self.history.save_snapshot(snapshot, label, &self.storage.tasks);
+    }
+
+    /// A label for a change to a task and its descendants, e.g. "deleted 'Refactor parser' (4 subtasks)".
+    fn describe_subtree(&self, verb: &str, task_id: TaskId) -> Option<String> {
+        let title = &self.storage.get_task(task_id)?.title;
+        let subtasks = self.storage.find_subtree_ids(task_id).len() - 1;
+        Some(label_with_subtasks(format!("{verb} '{title}'"), subtasks))
     }
 
     pub fn undo(&mut self) -> Option<()> {
         let current_snapshot = self.create_snapshot();
-        let snapshot_to_restore = self.history.undo(current_snapshot)?;
+        let undone = self.history.current_node().description.clone();
+        let snapshot_to_restore = self.history.undo(current_snapshot, &self.storage.tasks)?;
         self.restore_snapshot(snapshot_to_restore);
+        self.status_message = Some(format!("Undid: {undone}"));
         Some(())
     }
 
     pub fn redo(&mut self) -> Option<()> {
         let current_snapshot = self.create_snapshot();
-        let snapshot_to_restore = self.history.redo(current_snapshot)?;
+        let snapshot_to_restore = self.history.redo(current_snapshot, &self.storage.tasks)?;
         self.restore_snapshot(snapshot_to_restore);
+        self.status_message = Some(format!("Redid: {}", self.history.current_node().description));
         Some(())
     }
 
-    pub fn create_snapshot(&self) -> AppSnapshot {
+    /// Takes a snapshot of where the user is, the tasks are taken by the history as it's updated.
+    pub fn create_snapshot(&mut self) -> AppSnapshot {
+        self.history.sync(&self.storage.tasks);
         AppSnapshot {
-            tasks: self.storage.tasks.clone(),
+            changes: TaskChanges::default(),
             opened_task: self.storage.get_opened_task(),
             selected_index: self.storage.get_selected_position(),
+            taken_at: Utc::now(),
+        }
+    }
+
+    /// Shows the undo tree with the current state selected.
+    pub fn open_history(&mut self) {
+        self.history.sync(&self.storage.tasks);
+        let current = self.history.current();
+        let states = self.history.nodes_in_order();
+        let selected = states.iter().position(|&(node, _)| node == current).unwrap_or(0);
+        self.state = AppState::History {
+            states,
+            selected,
+            preview: None,
+        };
+    }
+
+    pub fn close_history(&mut self) {
+        self.state = AppState::Normal;
+    }
+
+    /// Moves the selection in the undo tree by `offset` states, staying within it.
+    pub fn move_history_selection(&mut self, offset: isize) {
+        if let AppState::History {
+            states,
+            selected,
+            preview,
+        } = &mut self.state
+        {
+            let new_selected = selected
+                .saturating_add_signed(offset)
+                .min(states.len().saturating_sub(1));
+            if new_selected != *selected
+                && let Some(&(node, _)) = states.get(new_selected)
+            {
+                *selected = new_selected;
+                *preview = self.history.changes_to(node);
+            }
+        }
+    }
+
+    /// The state selected in the undo tree, if it's shown.
+    pub fn selected_history_node(&self) -> Option<usize> {
+        match &self.state {
+            AppState::History { states, selected, .. } => states.get(*selected).map(|&(node, _)| node),
+            _ => None,
+        }
+    }
+
+    /// Brings the tasks to the state selected in the undo tree and closes it.
+    pub fn jump_to_selected_history_node(&mut self) -> Option<()> {
+        let node = self.selected_history_node()?;
+        self.close_history();
+
+        let current_snapshot = self.create_snapshot();
+        let snapshot_to_restore = self.history.jump_to(node, current_snapshot, &self.storage.tasks)?;
+        self.restore_snapshot(snapshot_to_restore);
+        Some(())
+    }
+
+    /// Saves the undo history so it's still there when nest is opened again.
+    pub fn save_history(&mut self) -> Result<()> {
+        self.history.sync(&self.storage.tasks);
+        match self.storage.history_file_path() {
+            Some(history_path) => self.history.save(&history_path),
+            None => Ok(()),
         }
     }
 
     pub fn restore_snapshot(&mut self, snapshot: AppSnapshot) {
-        self.storage.tasks = snapshot.tasks;
+        self.storage.restore_tasks(snapshot.changes);
         self.storage.view.set_opened_task(snapshot.opened_task);
         self.move_selection_to(snapshot.selected_index);
     }
 }
+
+/// A label for a change to a subtree that isn't in the tree, e.g. one being pasted.
+fn describe_subtree(verb: &str, subtree: &Subtree) -> Option<String> {
+    let title = &subtree.tasks.get(&subtree.root)?.title;
+    Some(label_with_subtasks(
+        format!("{verb} '{title}'"),
+        subtree.tasks.len() - 1,
+    ))
+}
+
+fn label_with_subtasks(label: String, subtasks: usize) -> String {
+    match subtasks {
+        0 => label,
+        1 => format!("{label} (1 subtask)"),
+        subtasks => format!("{label} ({subtasks} subtasks)"),
+    }
+}
@@ -0,0 +1,102 @@
+//! Command-line arguments.
+
+use std::{ffi::OsString, path::PathBuf};
+
+use color_eyre::{
+    Result,
+    eyre::{bail, eyre},
+};
+
+use crate::storage::{BackendKind, DEFAULT_BACKUPS_TO_KEEP};
+
+const USAGE: &str = "\
+Usage: nest [OPTIONS] [COMMAND]
+
+Commands:
+  restore [BACKUP]   List the backups of the state file, or restore BACKUP (a number from the list)
+
+Options:
+  -f, --file <PATH>  Use the state file at PATH (also set by $NEST_FILE)
+  -n, --name <NAME>  Use the nest called NAME, kept in $XDG_DATA_HOME/nest/
+  -b, --backups <N>  How many rolling backups to keep (also set by $NEST_BACKUPS, default 5)
+      --backend <KIND>
+                     Store the nest in a `json` file, an `sqlite` database or a `journal` of
+                     changes, guessed from the file extension by default (`.db`, `.sqlite` and
+                     `.sqlite3` are SQLite, `.jsonl` is a journal)
+  -r, --read-only    Browse the tree without saving, even if no other instance has it open
+  -h, --help         Print this message
+";
+
+#[derive(Debug, Default)]
+pub struct Args {
+    pub file: Option<PathBuf>,
+    pub name: Option<String>,
+    pub backups: usize,
+    pub backend: Option<BackendKind>,
+    pub read_only: bool,
+    pub command: Option<Command>,
+}
+
+#[derive(Debug)]
+pub enum Command {
+    Restore { backup: Option<String> },
+}
+
+impl Args {
+    pub fn parse() -> Result<Self> {
+        Self::parse_from(std::env::args_os().skip(1))
+    }
+
+    fn parse_from(args: impl IntoIterator<Item = OsString>) -> Result<Self> {
+        let mut parsed = Args::default();
+        let mut backups = std::env::var("NEST_BACKUPS").ok().filter(|backups| !backups.is_empty());
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let arg = arg.to_string_lossy().into_owned();
+
+            // Accept both `--flag value` and `--flag=value`
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_owned(), Some(value.to_owned())),
+                _ => (arg, None),
+            };
+            let mut value = || match inline_value.clone().map(OsString::from).or_else(|| args.next()) {
+                Some(value) => Ok(value),
+                None => bail!("missing value for `{flag}`\n\n{USAGE}"),
+            };
+
+            match flag.as_str() {
+                "-f" | "--file" => parsed.file = Some(PathBuf::from(value()?)),
+                "-n" | "--name" => parsed.name = Some(value()?.to_string_lossy().into_owned()),
+                "-b" | "--backups" => backups = Some(value()?.to_string_lossy().into_owned()),
+                "--backend" => {
+                    let backend = value()?.to_string_lossy().into_owned();
+                    parsed.backend =
+                        Some(BackendKind::from_name(&backend).ok_or_else(|| {
+                            eyre!("unknown backend `{backend}`, expected `json`, `sqlite` or `journal`")
+                        })?);
+                }
+                "-r" | "--read-only" => parsed.read_only = true,
+                "-h" | "--help" => {
+                    print!("{USAGE}");
+                    std::process::exit(0);
+                }
+                _ if flag.starts_with('-') => bail!("unexpected argument `{flag}`\n\n{USAGE}"),
+                "restore" if parsed.command.is_none() => parsed.command = Some(Command::Restore { backup: None }),
+                _ => match &mut parsed.command {
+                    Some(Command::Restore { backup: backup @ None }) => *backup = Some(flag),
+                    _ => bail!("unexpected argument `{flag}`\n\n{USAGE}"),
+                },
+            }
+        }
+
+        parsed.backups = match backups {
+            Some(backups) => backups
+                .parse()
+                .map_err(|_| eyre!("invalid amount of backups `{backups}`, expected a number"))?,
+            None => DEFAULT_BACKUPS_TO_KEEP,
+        };
+
+        Ok(parsed)
+    }
+}
@@ -0,0 +1,259 @@
+//! Due and scheduled dates, as typed by the user and as grouped in the agenda.
+
+use chrono::{Datelike, Days, Local, Months, NaiveDate, Weekday};
+
+use crate::entities::Task;
+
+/// The day it is where the user is, for dates typed relative to it.
+pub fn today() -> NaiveDate {
+    Local::now().date_naive()
+}
+
+/// Reads a date relative to `today`: "today", "tomorrow", a weekday like "fri" for the next one
+/// after today, an offset like "+3d", "+2w", "+1m" or "-1y", or an ISO date like "2026-11-02".
+pub fn parse_date(input: &str, today: NaiveDate) -> Option<NaiveDate> {
+    let input = input.trim().to_lowercase();
+    match input.as_str() {
+        "today" | "tod" => return Some(today),
+        "tomorrow" | "tom" => return today.checked_add_days(Days::new(1)),
+        "yesterday" => return today.checked_sub_days(Days::new(1)),
+        _ => {}
+    }
+
+    if let Ok(weekday) = input.parse::<Weekday>() {
+        let days_until = (weekday.num_days_from_monday() + 6 - today.weekday().num_days_from_monday()) % 7 + 1;
+        return today.checked_add_days(Days::new(days_until.into()));
+    }
+
+    if let Some(offset) = input.strip_prefix(['+', '-']) {
+        let (amount, unit) = offset.split_at(offset.find(|c: char| !c.is_ascii_digit())?);
+        let amount: u32 = amount.parse().ok()?;
+        let forward = input.starts_with('+');
+        return match (unit, forward) {
+            ("d", true) => today.checked_add_days(Days::new(amount.into())),
+            ("d", false) => today.checked_sub_days(Days::new(amount.into())),
+            ("w", true) => today.checked_add_days(Days::new(u64::from(amount) * 7)),
+            ("w", false) => today.checked_sub_days(Days::new(u64::from(amount) * 7)),
+            ("m", true) => today.checked_add_months(Months::new(amount)),
+            ("m", false) => today.checked_sub_months(Months::new(amount)),
+            ("y", true) => today.checked_add_months(Months::new(amount.checked_mul(12)?)),
+            ("y", false) => today.checked_sub_months(Months::new(amount.checked_mul(12)?)),
+            _ => None,
+        };
+    }
+
+    NaiveDate::parse_from_str(&input, "%Y-%m-%d").ok()
+}
+
+/// A date short enough for the list of tasks, the year only shown when it isn't this one.
+pub fn format_date(date: NaiveDate, today: NaiveDate) -> String {
+    match date - today {
+        delta if delta.num_days() == 0 => "today".to_owned(),
+        delta if delta.num_days() == 1 => "tomorrow".to_owned(),
+        delta if delta.num_days() == -1 => "yesterday".to_owned(),
+        _ if date.year() == today.year() => date.format("%b %d").to_string(),
+        _ => date.format("%b %d %Y").to_string(),
+    }
+}
+
+/// When a dated task comes up in the agenda, in the order they're shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AgendaGroup {
+    /// Due before today.
+    Overdue,
+    /// Due or scheduled today, or scheduled before and still open.
+    Today,
+    /// Up to the end of the week, Sunday included.
+    ThisWeek,
+    Later,
+}
+
+impl AgendaGroup {
+    pub fn label(self) -> &'static str {
+        match self {
+            AgendaGroup::Overdue => "Overdue",
+            AgendaGroup::Today => "Today",
+            AgendaGroup::ThisWeek => "This week",
+            AgendaGroup::Later => "Later",
+        }
+    }
+}
+
+/// Where an open task with a date goes in the agenda, with the date it's sorted by, the earliest
+/// of its due and scheduled dates.
+pub fn agenda_group(task: &Task, today: NaiveDate) -> Option<(AgendaGroup, NaiveDate)> {
+    if task.status.is_closed() {
+        return None;
+    }
+    let date = task.due.into_iter().chain(task.scheduled).min()?;
+
+    let end_of_week = today.checked_add_days(Days::new((6 - today.weekday().num_days_from_monday()).into()))?;
+    let group = match date {
+        _ if task.due.is_some_and(|due| due < today) => AgendaGroup::Overdue,
+        date if date <= today => AgendaGroup::Today,
+        date if date <= end_of_week => AgendaGroup::ThisWeek,
+        _ => AgendaGroup::Later,
+    };
+    Some((group, date))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::TaskStatus;
+
+    fn date(iso: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(iso, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn parses_dates_relative_to_today() {
+        // A Wednesday
+        let today = date("2026-10-14");
+        let cases = [
+            ("today", "2026-10-14"),
+            ("Tom", "2026-10-15"),
+            ("yesterday", "2026-10-13"),
+            ("thu", "2026-10-15"),
+            ("sun", "2026-10-18"),
+            ("Monday", "2026-10-19"),
+            ("tue", "2026-10-20"),
+            ("wed", "2026-10-21"),
+            ("+3d", "2026-10-17"),
+            ("-3d", "2026-10-11"),
+            ("+2w", "2026-10-28"),
+            ("-1w", "2026-10-07"),
+            ("+1m", "2026-11-14"),
+            ("-1m", "2026-09-14"),
+            ("+1y", "2027-10-14"),
+            ("-1y", "2025-10-14"),
+            ("2026-11-02", "2026-11-02"),
+            (" 2026-11-02 ", "2026-11-02"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(parse_date(input, today), Some(date(expected)), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn clamps_offsets_to_the_end_of_the_month() {
+        let cases = [
+            ("2027-01-31", "+1m", "2027-02-28"),
+            ("2026-03-31", "-1m", "2026-02-28"),
+            ("2026-08-31", "+1m", "2026-09-30"),
+            ("2028-02-29", "-1y", "2027-02-28"),
+            ("2028-02-29", "+4y", "2032-02-29"),
+        ];
+        for (today, input, expected) in cases {
+            assert_eq!(
+                parse_date(input, date(today)),
+                Some(date(expected)),
+                "{input:?} from {today}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_dates() {
+        let today = date("2026-10-14");
+        let cases = [
+            "",
+            "   ",
+            "+",
+            "+d",
+            "3d",
+            "+3x",
+            "+-3d",
+            "+99999999999d",
+            "next week",
+            "2026-02-30",
+        ];
+        for input in cases {
+            assert_eq!(parse_date(input, today), None, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn groups_tasks_up_to_the_end_of_the_week() {
+        let task = |due: Option<&str>, scheduled: Option<&str>| Task {
+            due: due.map(date),
+            scheduled: scheduled.map(date),
+            ..Task::default()
+        };
+        // From a Wednesday, a Sunday and a Monday
+        let cases = [
+            (
+                "2026-10-14",
+                task(Some("2026-10-13"), None),
+                AgendaGroup::Overdue,
+                "2026-10-13",
+            ),
+            (
+                "2026-10-14",
+                task(None, Some("2026-10-13")),
+                AgendaGroup::Today,
+                "2026-10-13",
+            ),
+            (
+                "2026-10-14",
+                task(Some("2026-10-14"), None),
+                AgendaGroup::Today,
+                "2026-10-14",
+            ),
+            (
+                "2026-10-14",
+                task(Some("2026-10-18"), None),
+                AgendaGroup::ThisWeek,
+                "2026-10-18",
+            ),
+            (
+                "2026-10-14",
+                task(Some("2026-10-19"), None),
+                AgendaGroup::Later,
+                "2026-10-19",
+            ),
+            (
+                "2026-10-14",
+                task(Some("2026-10-20"), Some("2026-10-15")),
+                AgendaGroup::ThisWeek,
+                "2026-10-15",
+            ),
+            (
+                "2026-10-18",
+                task(Some("2026-10-18"), None),
+                AgendaGroup::Today,
+                "2026-10-18",
+            ),
+            (
+                "2026-10-18",
+                task(Some("2026-10-19"), None),
+                AgendaGroup::Later,
+                "2026-10-19",
+            ),
+            (
+                "2026-10-19",
+                task(None, Some("2026-10-25")),
+                AgendaGroup::ThisWeek,
+                "2026-10-25",
+            ),
+            (
+                "2026-10-19",
+                task(None, Some("2026-10-26")),
+                AgendaGroup::Later,
+                "2026-10-26",
+            ),
+        ];
+        for (today, task, group, sorted_by) in cases {
+            let expected = Some((group, date(sorted_by)));
+            assert_eq!(agenda_group(&task, date(today)), expected, "{task:?} on {today}");
+        }
+
+        let today = date("2026-10-14");
+        assert_eq!(agenda_group(&task(None, None), today), None);
+        let done = Task {
+            status: TaskStatus::Done,
+            ..task(Some("2026-10-13"), None)
+        };
+        assert_eq!(agenda_group(&done, today), None);
+    }
+}
@@ -1,29 +1,210 @@
-use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    sync::{Mutex, PoisonError},
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use indexmap::IndexMap;
+use serde::{Deserialize, Deserializer, Serialize, de};
+use ulid::{Generator, Ulid};
+
+/// Identifies a task for good, ids are never reused and sort by creation time.
+///
+/// Serialized as the ULID string, integer ids from older versions are still read, see `from_legacy`.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(transparent)]
+pub struct TaskId(Ulid);
+
+/// Keeps ids made in the same millisecond in order.
+static ID_GENERATOR: Mutex<Generator> = Mutex::new(Generator::new());
+
+impl TaskId {
+    pub fn new() -> Self {
+        let mut generator = ID_GENERATOR.lock().unwrap_or_else(PoisonError::into_inner);
+        // Only fails after 2^80 ids in a single millisecond
+        TaskId(generator.generate().unwrap_or_else(|_| Ulid::new()))
+    }
+
+    /// The id a task had before ids were ULIDs, mapped to a ULID with a zero timestamp so every
+    /// reference to it maps to the same one.
+    pub fn from_legacy(id: u64) -> Self {
+        TaskId(Ulid::from_parts(0, id.into()))
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<'de> Deserialize<'de> for TaskId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TaskIdVisitor;
+
+        impl de::Visitor<'_> for TaskIdVisitor {
+            type Value = TaskId;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a ULID or an integer task id")
+            }
+
+            fn visit_u64<E: de::Error>(self, id: u64) -> Result<TaskId, E> {
+                Ok(TaskId::from_legacy(id))
+            }
+
+            fn visit_str<E: de::Error>(self, id: &str) -> Result<TaskId, E> {
+                // Map keys are always strings, even for integer ids
+                match (Ulid::from_string(id), id.parse()) {
+                    (Ok(ulid), _) => Ok(TaskId(ulid)),
+                    (Err(_), Ok(legacy_id)) => Ok(TaskId::from_legacy(legacy_id)),
+                    (Err(error), Err(_)) => Err(E::custom(format!("invalid task id `{id}`: {error}"))),
+                }
+            }
+        }
+
+        deserializer.deserialize_any(TaskIdVisitor)
+    }
+}
+
+/// Where a task is in its workflow.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    #[default]
+    Todo,
+    InProgress,
+    /// Can't go on until something else is done.
+    Blocked,
+    /// Waiting on someone else.
+    Waiting,
+    Done,
+    /// Dropped without being done.
+    Cancelled,
+}
+
+impl TaskStatus {
+    /// Every status, in the order they're cycled through.
+    pub const ALL: [TaskStatus; 6] = [
+        TaskStatus::Todo,
+        TaskStatus::InProgress,
+        TaskStatus::Blocked,
+        TaskStatus::Waiting,
+        TaskStatus::Done,
+        TaskStatus::Cancelled,
+    ];
+
+    /// Whether nothing is left to do on the task, cancelled tasks are closed without being done.
+    pub fn is_closed(self) -> bool {
+        matches!(self, TaskStatus::Done | TaskStatus::Cancelled)
+    }
+
+    /// The status after this one in `ALL`, wrapping around.
+    pub fn next(self) -> Self {
+        let position = Self::ALL.iter().position(|&status| status == self).unwrap_or(0);
+        Self::ALL[(position + 1) % Self::ALL.len()]
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            TaskStatus::Todo => "to do",
+            TaskStatus::InProgress => "in progress",
+            TaskStatus::Blocked => "blocked",
+            TaskStatus::Waiting => "waiting",
+            TaskStatus::Done => "done",
+            TaskStatus::Cancelled => "cancelled",
+        }
+    }
+}
 
 #[derive(Default, Debug, Clone, PartialEq)]
 pub struct TaskData {
     pub title: String,
-    pub children: Vec<u64>,
-    pub done: bool,
+    pub children: Vec<TaskId>,
+    pub status: TaskStatus,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Task {
-    pub id: u64,
+    pub id: TaskId,
     pub parent: ParentTask,
     pub title: String,
-    pub children: Vec<u64>,
-    pub done: bool,
+    pub children: Vec<TaskId>,
+    pub status: TaskStatus,
+    /// Free-form text with more context than fits in the title, empty if there's none.
+    #[serde(default)]
+    pub notes: String,
+    /// When it has to be done by.
+    #[serde(default)]
+    pub due: Option<NaiveDate>,
+    /// When to start working on it.
+    #[serde(default)]
+    pub scheduled: Option<NaiveDate>,
+    /// `None` for tasks saved before it was kept, like the other timestamps.
+    #[serde(default)]
+    pub created_at: Option<DateTime<Utc>>,
+    /// Last time the title, the notes, the status, the dates or the position of the task changed.
+    #[serde(default)]
+    pub updated_at: Option<DateTime<Utc>>,
+    /// When it was marked done or cancelled, `None` while it's open.
+    #[serde(default)]
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+/// The dates a task can be given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DateKind {
+    Due,
+    Scheduled,
+}
+
+impl DateKind {
+    pub fn label(self) -> &'static str {
+        match self {
+            DateKind::Due => "due date",
+            DateKind::Scheduled => "scheduled date",
+        }
+    }
+}
+
+/// Characters starting a tag in a title, as in `#bug`, `@alice` or `+release`.
+pub const TAG_SIGILS: [char; 3] = ['#', '@', '+'];
+
+/// Whether a word is a tag, a sigil followed by letters, digits, `-`, `_`, `.` or `/`.
+pub fn is_tag(word: &str) -> bool {
+    let mut chars = word.chars();
+    chars.next().is_some_and(|sigil| TAG_SIGILS.contains(&sigil))
+        && !chars.as_str().is_empty()
+        && chars.all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'))
 }
 
 impl Task {
+    /// The tags in the title, sigil included, in the order they're written.
+    pub fn tags(&self) -> impl Iterator<Item = &str> {
+        self.title.split_whitespace().filter(|word| is_tag(word))
+    }
+
+    pub fn date(&self, kind: DateKind) -> Option<NaiveDate> {
+        match kind {
+            DateKind::Due => self.due,
+            DateKind::Scheduled => self.scheduled,
+        }
+    }
+
+    pub fn date_mut(&mut self, kind: DateKind) -> &mut Option<NaiveDate> {
+        match kind {
+            DateKind::Due => &mut self.due,
+            DateKind::Scheduled => &mut self.scheduled,
+        }
+    }
+
     pub fn with_data(self, data: TaskData) -> Self {
         Task {
-            id: self.id,
-            parent: self.parent,
             title: data.title,
             children: data.children,
-            done: data.done,
+            status: data.status,
+            ..self
         }
     }
 }
@@ -33,7 +214,7 @@ impl From<Task> for TaskData {
         TaskData {
             title: data.title,
             children: data.children,
-            done: data.done,
+            status: data.status,
         }
     }
 }
@@ -42,5 +223,12 @@ impl From<Task> for TaskData {
 pub enum ParentTask {
     #[default]
     Root,
-    Id(u64),
+    Id(TaskId),
+}
+
+/// A task detached from the tree together with all its descendants.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subtree {
+    pub root: TaskId,
+    pub tasks: IndexMap<TaskId, Task>,
 }
@@ -0,0 +1,150 @@
+//! Differences between two versions of the tasks, so undo steps only keep the tasks they touched.
+
+use std::collections::HashSet;
+
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use crate::entities::{Task, TaskId};
+
+/// Turns one version of the tasks into another, by putting back the tasks that differ as they are
+/// in the other one, with their position in the map.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TaskChanges(Vec<(TaskId, Option<(usize, Task)>)>);
+
+impl TaskChanges {
+    /// The changes turning `from` into `to`.
+    ///
+    /// Besides the tasks that were added, removed or edited, tasks that kept their data but moved
+    /// around the others are included, so the order of root tasks comes back too.
+    pub fn between(from: &IndexMap<TaskId, Task>, to: &IndexMap<TaskId, Task>) -> Self {
+        let mut changed: HashSet<TaskId> = from
+            .iter()
+            .filter(|&(id, task)| to.get(id) != Some(task))
+            .map(|(id, _)| *id)
+            .chain(to.keys().filter(|id| !from.contains_key(*id)).copied())
+            .collect();
+
+        // The others are in both, only the part where their order differs needs to be put back
+        let from_kept: Vec<TaskId> = from.keys().filter(|id| !changed.contains(*id)).copied().collect();
+        let to_kept: Vec<TaskId> = to.keys().filter(|id| !changed.contains(*id)).copied().collect();
+        let same_start = from_kept
+            .iter()
+            .zip(&to_kept)
+            .take_while(|(from, to)| from == to)
+            .count();
+        let same_end = from_kept[same_start..]
+            .iter()
+            .rev()
+            .zip(to_kept[same_start..].iter().rev())
+            .take_while(|(from, to)| from == to)
+            .count();
+        changed.extend(&to_kept[same_start..to_kept.len() - same_end]);
+
+        let mut changes: Vec<_> = changed
+            .into_iter()
+            .map(|id| (id, to.get_full(&id).map(|(index, _, task)| (index, task.clone()))))
+            .collect();
+        changes.sort_by_key(|(id, _)| *id);
+        TaskChanges(changes)
+    }
+
+    /// The tasks that differ, whether they were added, removed, edited or moved.
+    pub fn ids(&self) -> impl Iterator<Item = TaskId> + '_ {
+        self.0.iter().map(|(id, _)| *id)
+    }
+
+    /// Applies the changes to the version of the tasks they were made from.
+    pub fn apply(&self, tasks: &mut IndexMap<TaskId, Task>) {
+        for (id, _) in &self.0 {
+            tasks.shift_remove(id);
+        }
+
+        // Inserting in order of position puts each task where it was, as the ones before it are
+        // already there
+        let mut restored: Vec<_> = self
+            .0
+            .iter()
+            .filter_map(|(id, task)| task.as_ref().map(|(index, task)| (*index, *id, task)))
+            .collect();
+        restored.sort_by_key(|(index, _, _)| *index);
+        for (index, id, task) in restored {
+            let index = index.min(tasks.len());
+            tasks.shift_insert(index, id, task.clone());
+        }
+    }
+}
+
+/// A task that's different after some changes, tasks that only moved among their siblings or
+/// whose children changed are left out.
+pub enum TaskChange<'a> {
+    Added(&'a Task),
+    Removed(&'a Task),
+    Edited { before: &'a Task, after: &'a Task },
+}
+
+impl TaskChanges {
+    /// How the changes affect the tasks in `from`, in the order the tasks were made.
+    pub fn task_changes<'a>(&'a self, from: &'a IndexMap<TaskId, Task>) -> Vec<TaskChange<'a>> {
+        self.0
+            .iter()
+            .filter_map(|(id, after)| match (from.get(id), after) {
+                (None, Some((_, after))) => Some(TaskChange::Added(after)),
+                (Some(before), None) => Some(TaskChange::Removed(before)),
+                (Some(before), Some((_, after)))
+                    if (
+                        &before.title,
+                        before.status,
+                        &before.notes,
+                        before.due,
+                        before.scheduled,
+                        before.parent,
+                    ) != (
+                        &after.title,
+                        after.status,
+                        &after.notes,
+                        after.due,
+                        after.scheduled,
+                        after.parent,
+                    ) =>
+                {
+                    Some(TaskChange::Edited { before, after })
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// A short description of the changes to `from`, e.g. to list them in the history.
+    pub fn describe(&self, from: &IndexMap<TaskId, Task>) -> String {
+        let task_changes = self.task_changes(from);
+        let Some(first) = task_changes.first() else {
+            return "reordered tasks".to_owned();
+        };
+
+        let description = match first {
+            TaskChange::Added(task) => format!("added '{}'", task.title),
+            TaskChange::Removed(task) => format!("removed '{}'", task.title),
+            TaskChange::Edited { before, after } if before.title != after.title => {
+                format!("renamed '{}' to '{}'", before.title, after.title)
+            }
+            TaskChange::Edited { before, after } if before.status != after.status => {
+                format!("marked '{}' {}", after.title, after.status.label())
+            }
+            TaskChange::Edited { before, after } if before.notes != after.notes => {
+                format!("edited the notes of '{}'", after.title)
+            }
+            TaskChange::Edited { before, after } if (before.due, before.scheduled) != (after.due, after.scheduled) => {
+                format!("changed the dates of '{}'", after.title)
+            }
+            TaskChange::Edited { after, .. } => format!("moved '{}'", after.title),
+        };
+
+        match task_changes.len() {
+            1 => description,
+            2 => format!("{description} and 1 more task"),
+            count => format!("{description} and {} more tasks", count - 1),
+        }
+    }
+}
@@ -0,0 +1,431 @@
+use std::path::Path;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use color_eyre::Result;
+use fs_err as fs;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    entities::{ParentTask, Task, TaskId},
+    storage,
+};
+
+mod changes;
+
+pub use changes::{TaskChange, TaskChanges};
+
+/// States kept between sessions, the oldest ones are dropped first.
+const MAX_STATES: usize = 200;
+
+/// Changes older than this can't be undone once the history is saved.
+const MAX_AGE: TimeDelta = TimeDelta::days(30);
+
+/// Where the user was, together with the changes bringing the tasks there when restored.
+#[derive(Clone)]
+pub struct AppSnapshot {
+    pub changes: TaskChanges,
+    pub opened_task: ParentTask,
+    pub selected_index: Option<usize>,
+    pub taken_at: DateTime<Utc>,
+}
+
+/// A state of the tasks in the undo tree, each change made from a state adds a child to it.
+#[derive(Serialize, Deserialize)]
+pub struct HistoryNode {
+    /// `None` for the oldest state kept.
+    pub parent: Option<usize>,
+    /// Changes from the tasks of the parent to the ones of this state.
+    redo: TaskChanges,
+    /// Changes from the tasks of this state to the ones of the parent.
+    undo: TaskChanges,
+    /// What changed from the parent.
+    pub description: String,
+    /// When it was changed from the parent.
+    pub changed_at: DateTime<Utc>,
+    /// Where the user was when they last left this state, to put them back there.
+    opened_task: ParentTask,
+    selected_index: Option<usize>,
+    /// The child redo goes to, the last one made or left by undoing.
+    last_child: Option<usize>,
+}
+
+/// Every state the tasks went through, as a tree so undoing and then making a change doesn't
+/// lose the states that were undone.
+///
+/// Only the tasks of the current state are kept in full, the others are the changes from their
+/// parent.
+#[derive(Serialize, Deserialize)]
+pub struct AppHistory {
+    base: IndexMap<TaskId, Task>,
+    nodes: Vec<HistoryNode>,
+    current: usize,
+    /// Taken when a change was started, describing the state it's made from, with a label for
+    /// the change.
+    #[serde(skip)]
+    pending: Option<(AppSnapshot, String)>,
+}
+
+/// The history as saved next to the state file.
+#[derive(Serialize, Deserialize)]
+struct HistoryFile<H> {
+    /// Version of the state file format the tasks are saved in.
+    version: u64,
+    #[serde(flatten)]
+    history: H,
+}
+
+impl AppHistory {
+    pub fn new(tasks: &IndexMap<TaskId, Task>) -> Self {
+        let root = HistoryNode {
+            parent: None,
+            redo: TaskChanges::default(),
+            undo: TaskChanges::default(),
+            description: "opened".to_owned(),
+            changed_at: Utc::now(),
+            opened_task: ParentTask::Root,
+            selected_index: None,
+            last_child: None,
+        };
+
+        Self {
+            base: tasks.clone(),
+            nodes: vec![root],
+            current: 0,
+            pending: None,
+        }
+    }
+
+    /// Loads the history saved by a previous session, starting over if there's none or it can't
+    /// be read, as losing it shouldn't keep nest from opening.
+    pub fn load(file_path: &Path, tasks: &IndexMap<TaskId, Task>) -> Self {
+        let Ok(json) = fs::read_to_string(file_path) else {
+            return Self::new(tasks);
+        };
+
+        match serde_json::from_str::<HistoryFile<AppHistory>>(&json) {
+            // Tasks saved in another format would be restored wrong, so only keep the current one
+            Ok(file) if file.version == storage::CURRENT_VERSION && file.history.current < file.history.nodes.len() => {
+                let mut history = file.history;
+                // The tasks may have been changed since, e.g. by another program
+                history.sync(tasks);
+                history
+            }
+            _ => Self::new(tasks),
+        }
+    }
+
+    /// Saves the history for the next session, without the states over `MAX_STATES` or `MAX_AGE`.
+    pub fn save(&mut self, file_path: &Path) -> Result<()> {
+        self.prune(Utc::now());
+
+        let file = HistoryFile {
+            version: storage::CURRENT_VERSION,
+            history: &*self,
+        };
+        let json = serde_json::to_vec(&file)?;
+        storage::write_atomically(file_path, &json)
+    }
+
+    /// Drops the oldest states by moving the root towards the current state, the branches off
+    /// the states dropped go with them.
+    fn prune(&mut self, now: DateTime<Utc>) {
+        let path = self.path_from_root(self.current);
+        let subtree_sizes = self.subtree_sizes();
+        let mut root_position = 0;
+        while let Some(&next) = path.get(root_position + 1) {
+            let too_old = now - self.nodes[next].changed_at > MAX_AGE;
+            let too_many = subtree_sizes[path[root_position]] > MAX_STATES;
+            if !too_old && !too_many {
+                break;
+            }
+            root_position += 1;
+        }
+
+        if root_position > 0 {
+            self.keep_subtree(path[root_position]);
+        }
+    }
+
+    /// States from `root` down, parents before their children.
+    fn subtree(&self, root: usize) -> Vec<usize> {
+        let mut subtree = vec![root];
+        let mut position = 0;
+        while let Some(&node) = subtree.get(position) {
+            subtree.extend(self.children(node));
+            position += 1;
+        }
+        subtree
+    }
+
+    /// How many states there are from each state down, itself included.
+    fn subtree_sizes(&self) -> Vec<usize> {
+        let mut sizes = vec![1; self.nodes.len()];
+        // Children always come after their parent, so they're counted before it
+        for node in (0..self.nodes.len()).rev() {
+            if let Some(parent) = self.nodes[node].parent {
+                sizes[parent] += sizes[node];
+            }
+        }
+        sizes
+    }
+
+    fn children(&self, node: usize) -> impl Iterator<Item = usize> + '_ {
+        (0..self.nodes.len()).filter(move |&child| self.nodes[child].parent == Some(node))
+    }
+
+    /// Makes `root` the oldest state, dropping the ones that aren't below it.
+    fn keep_subtree(&mut self, root: usize) {
+        let kept = self.subtree(root);
+        let new_index = |old: usize| kept.iter().position(|&node| node == old);
+
+        let mut nodes: Vec<_> = std::mem::take(&mut self.nodes).into_iter().map(Some).collect();
+        self.nodes = kept
+            .iter()
+            .filter_map(|&old| nodes[old].take())
+            .map(|mut node| {
+                node.parent = node.parent.and_then(new_index);
+                node.last_child = node.last_child.and_then(new_index);
+                node
+            })
+            .collect();
+
+        let root = &mut self.nodes[0];
+        root.redo = TaskChanges::default();
+        root.undo = TaskChanges::default();
+        self.current = new_index(self.current).unwrap_or(0);
+    }
+
+    /// Records the tasks as a new state if they changed since the current one.
+    pub fn sync(&mut self, tasks: &IndexMap<TaskId, Task>) {
+        if self.base.iter().eq(tasks) {
+            return;
+        }
+
+        let redo = TaskChanges::between(&self.base, tasks);
+        let undo = TaskChanges::between(tasks, &self.base);
+        let parent = &self.nodes[self.current];
+        let (opened_task, selected_index, changed_at, description) = match self.pending.take() {
+            Some((snapshot, label)) => (snapshot.opened_task, snapshot.selected_index, snapshot.taken_at, label),
+            // Not made through `App`, e.g. reloaded from the disk
+            None => (
+                parent.opened_task,
+                parent.selected_index,
+                Utc::now(),
+                redo.describe(&self.base),
+            ),
+        };
+
+        redo.apply(&mut self.base);
+        self.nodes.push(HistoryNode {
+            parent: Some(self.current),
+            redo,
+            undo,
+            description,
+            changed_at,
+            opened_task,
+            selected_index,
+            last_child: None,
+        });
+        let new_node = self.nodes.len() - 1;
+        self.nodes[self.current].last_child = Some(new_node);
+        self.current = new_node;
+    }
+
+    /// Starts a change described by `label` from where the user is as of `snapshot`, `tasks`
+    /// being how they are now.
+    pub fn save_snapshot(&mut self, snapshot: AppSnapshot, label: String, tasks: &IndexMap<TaskId, Task>) {
+        self.leave_current(&snapshot);
+        self.pending = Some((snapshot, label));
+        self.sync(tasks);
+    }
+
+    /// Goes back to the parent of the current state, the changes of the snapshot returned apply
+    /// to `tasks`.
+    pub fn undo(&mut self, current_snapshot: AppSnapshot, tasks: &IndexMap<TaskId, Task>) -> Option<AppSnapshot> {
+        self.sync(tasks);
+        let parent = self.nodes[self.current].parent?;
+        self.leave_current(&current_snapshot);
+
+        let changes = self.nodes[self.current].undo.clone();
+        self.nodes[parent].last_child = Some(self.current);
+        Some(self.move_to(parent, changes))
+    }
+
+    /// Goes forward to the last child of the current state, the changes of the snapshot returned
+    /// apply to `tasks`.
+    pub fn redo(&mut self, current_snapshot: AppSnapshot, tasks: &IndexMap<TaskId, Task>) -> Option<AppSnapshot> {
+        self.sync(tasks);
+        let child = self.nodes[self.current].last_child?;
+        self.leave_current(&current_snapshot);
+
+        let changes = self.nodes[child].redo.clone();
+        Some(self.move_to(child, changes))
+    }
+
+    /// Goes to any state, the changes of the snapshot returned apply to `tasks`.
+    pub fn jump_to(
+        &mut self,
+        node: usize,
+        current_snapshot: AppSnapshot,
+        tasks: &IndexMap<TaskId, Task>,
+    ) -> Option<AppSnapshot> {
+        self.nodes.get(node)?;
+        self.sync(tasks);
+        self.leave_current(&current_snapshot);
+
+        self.base = self.tasks_at(node);
+        self.current = node;
+        // Redo follows the way taken
+        for pair in self.path_from_root(node).windows(2) {
+            self.nodes[pair[0]].last_child = Some(pair[1]);
+        }
+
+        let node = &self.nodes[node];
+        Some(AppSnapshot {
+            changes: TaskChanges::between(tasks, &self.base),
+            opened_task: node.opened_task,
+            selected_index: node.selected_index,
+            taken_at: Utc::now(),
+        })
+    }
+
+    fn leave_current(&mut self, snapshot: &AppSnapshot) {
+        let current = &mut self.nodes[self.current];
+        current.opened_task = snapshot.opened_task;
+        current.selected_index = snapshot.selected_index;
+    }
+
+    /// Moves to a state next to the current one, `changes` leading to its tasks.
+    fn move_to(&mut self, node: usize, changes: TaskChanges) -> AppSnapshot {
+        changes.apply(&mut self.base);
+        self.current = node;
+
+        let node = &self.nodes[node];
+        AppSnapshot {
+            changes,
+            opened_task: node.opened_task,
+            selected_index: node.selected_index,
+            taken_at: Utc::now(),
+        }
+    }
+
+    /// States from the root to `node`, both included.
+    fn path_from_root(&self, node: usize) -> Vec<usize> {
+        let mut path: Vec<_> = std::iter::successors(Some(node), |&node| self.nodes[node].parent).collect();
+        path.reverse();
+        path
+    }
+
+    /// The tasks as they are in a state, undoing up to the state it shares with the current one,
+    /// then redoing down to it.
+    pub fn tasks_at(&self, node: usize) -> IndexMap<TaskId, Task> {
+        let current_path = self.path_from_root(self.current);
+        let node_path = self.path_from_root(node);
+        let shared = current_path.iter().zip(&node_path).take_while(|(a, b)| a == b).count();
+
+        let mut tasks = self.base.clone();
+        for &undone in current_path[shared..].iter().rev() {
+            self.nodes[undone].undo.apply(&mut tasks);
+        }
+        for &redone in &node_path[shared..] {
+            self.nodes[redone].redo.apply(&mut tasks);
+        }
+        tasks
+    }
+
+    /// The changes from the tasks of the current state to the ones of `node`, `None` if it's the
+    /// current one.
+    pub fn changes_to(&self, node: usize) -> Option<TaskChanges> {
+        (node != self.current).then(|| TaskChanges::between(&self.base, &self.tasks_at(node)))
+    }
+
+    /// The tasks of the current state.
+    pub fn current_tasks(&self) -> &IndexMap<TaskId, Task> {
+        &self.base
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    pub fn current_node(&self) -> &HistoryNode {
+        &self.nodes[self.current]
+    }
+
+    pub fn node(&self, node: usize) -> Option<&HistoryNode> {
+        self.nodes.get(node)
+    }
+
+    /// Every state with how many branches deep it is, oldest first, the branches off a state
+    /// coming after the rest of the branch it's on.
+    pub fn nodes_in_order(&self) -> Vec<(usize, usize)> {
+        let mut in_order = Vec::new();
+        let mut to_visit = vec![(0, 0)];
+        while let Some((node, depth)) = to_visit.pop() {
+            in_order.push((node, depth));
+            // The first child continues the branch, the others start new ones
+            let children: Vec<_> = self.children(node).collect();
+            for (position, &child) in children.iter().enumerate().rev() {
+                to_visit.push((child, depth + usize::from(position > 0)));
+            }
+        }
+        in_order
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flat_tree(size: usize) -> IndexMap<TaskId, Task> {
+        (0..size)
+            .map(|number| {
+                let task = Task {
+                    id: TaskId::new(),
+                    title: format!("Task {number}"),
+                    ..Task::default()
+                };
+                (task.id, task)
+            })
+            .collect()
+    }
+
+    /// Renames `tasks_per_edit` tasks of a tree of `tree_size` tasks, 2000 times, then prunes the
+    /// history as when saving it.
+    fn history_after_edits(tree_size: usize, tasks_per_edit: usize) -> AppHistory {
+        let mut tasks = flat_tree(tree_size);
+        let mut history = AppHistory::new(&tasks);
+        for edit in 0..2000 {
+            for renamed in 0..tasks_per_edit {
+                let (_, task) = tasks.get_index_mut((edit * 7 + renamed) % tree_size).unwrap();
+                task.title = format!("Edit {edit}");
+            }
+            history.sync(&tasks);
+        }
+        assert_eq!(history.nodes.len(), 2001);
+        history.prune(Utc::now());
+        assert!(history.base.iter().eq(&tasks));
+        history
+    }
+
+    fn states_size(history: &AppHistory) -> usize {
+        serde_json::to_vec(&history.nodes).unwrap().len()
+    }
+
+    #[test]
+    fn keeps_a_bounded_history_sized_by_the_changes() {
+        let small_tree = history_after_edits(20, 1);
+        let big_tree = history_after_edits(200, 1);
+        let big_changes = history_after_edits(20, 10);
+
+        for history in [&small_tree, &big_tree, &big_changes] {
+            assert!(history.nodes.len() <= MAX_STATES);
+            assert_eq!(history.current, history.nodes.len() - 1);
+        }
+
+        // The states only hold the tasks that changed, however many there are in the tree
+        assert!(states_size(&big_tree) < states_size(&small_tree) * 11 / 10);
+        assert!(states_size(&big_changes) > states_size(&small_tree) * 5);
+    }
+}
@@ -1,30 +1,50 @@
 mod app;
+mod cli;
 #[cfg(feature = "climsg")]
 mod climsg;
+mod dates;
 mod entities;
 mod history;
 mod log;
+mod recovery;
 mod render;
+mod restore;
 mod storage;
 
 use std::{
     io::{self},
     ops::ControlFlow,
+    panic::{self, AssertUnwindSafe},
 };
 
 use app::{App, AppState};
+use cli::Command;
 use color_eyre::Result;
 use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
-use ratatui::{Terminal, backend::CrosstermBackend, crossterm::event::KeyModifiers};
+use entities::{DateKind, TaskStatus};
+use ratatui::{
+    Terminal,
+    backend::CrosstermBackend,
+    crossterm::event::{KeyCode::*, KeyEvent, KeyModifiers},
+};
 
-use self::render::render_app;
+use self::{render::render_app, storage::BackendKind};
 
 fn main() -> Result<()> {
     color_eyre::install()?;
 
-    let storage = storage::AppStorage::load_state()?;
+    let args = cli::Args::parse()?;
+    let file_path = storage::resolve_file_path(args.file, args.name.as_deref(), args.backend)?;
+    let config = storage::StorageConfig {
+        backend: args.backend.unwrap_or_else(|| BackendKind::from_path(&file_path)),
+        file_path,
+        backups_to_keep: args.backups,
+        read_only: args.read_only,
+    };
 
-    let app = App::new(storage);
+    if let Some(Command::Restore { backup }) = args.command {
+        return restore::run(&config, backup.as_deref());
+    }
 
     // Setup
     let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
@@ -32,7 +52,10 @@ fn main() -> Result<()> {
     enable_raw_mode()?;
     terminal.hide_cursor()?;
 
-    let result = run(app, &mut terminal);
+    let result = recovery::load_or_recover(config, &mut terminal).and_then(|storage| match storage {
+        Some(storage) => run_and_flush(App::new(storage), &mut terminal),
+        None => Ok(()),
+    });
 
     // Cleanup
     terminal.clear()?;
@@ -42,16 +65,50 @@ fn main() -> Result<()> {
     result
 }
 
-fn run(mut app: App, terminal: &mut Terminal<CrosstermBackend<io::Stdout>>) -> Result<()> {
+/// Runs the app, making sure unsaved changes and the undo history are written when it quits or
+/// panics.
+fn run_and_flush(mut app: App, terminal: &mut Terminal<CrosstermBackend<io::Stdout>>) -> Result<()> {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| run(&mut app, terminal)));
+    let flush_result = app.storage.flush().and(app.save_history());
+
+    match result {
+        Ok(result) => result.and(flush_result),
+        Err(panic_payload) => panic::resume_unwind(panic_payload),
+    }
+}
+
+fn run(app: &mut App, terminal: &mut Terminal<CrosstermBackend<io::Stdout>>) -> Result<()> {
+    #[cfg(feature = "climsg")]
+    let mut climsg_task_id = None;
+
     loop {
-        terminal.draw(|frame| render_app(frame, &mut app))?;
+        terminal.draw(|frame| render_app(frame, app))?;
 
-        let flow = handle_input(&mut app)?;
+        // Wake up to save pending changes and pick up external ones even if no key is pressed
+        let has_event = match app.storage.time_until_update() {
+            Some(timeout) => ratatui::crossterm::event::poll(timeout)?,
+            None => true,
+        };
+        let flow = if has_event {
+            handle_input(app)?
+        } else {
+            ControlFlow::Continue(())
+        };
 
-        app.storage.save()?;
+        app.storage.update()?;
+        if let Some(message) = app.storage.take_sync_message() {
+            app.status_message = Some(message);
+        }
 
+        // Idle wakeups only need a message if a reload changed the selection
         #[cfg(feature = "climsg")]
-        climsg::send_message(app.get_selected_task().filter(|_| flow == ControlFlow::Continue(())));
+        {
+            let task = app.get_selected_task().filter(|_| flow == ControlFlow::Continue(()));
+            if has_event || task.map(|task| task.id) != climsg_task_id {
+                climsg_task_id = task.map(|task| task.id);
+                climsg::send_message(task);
+            }
+        }
 
         if flow == ControlFlow::Break(()) {
             break Ok(());
@@ -60,42 +117,35 @@ fn run(mut app: App, terminal: &mut Terminal<CrosstermBackend<io::Stdout>>) -> R
 }
 
 fn handle_input(app: &mut App) -> Result<ControlFlow<()>> {
-    use ratatui::crossterm::{
-        event,
-        event::{KeyCode::*, KeyEventKind},
-    };
+    use ratatui::crossterm::{event, event::KeyEventKind};
 
     if let event::Event::Key(key) = event::read()? {
         match app.state {
-            AppState::Normal if key.kind == KeyEventKind::Press => match key.code {
-                Char('q') => return Ok(ControlFlow::Break(())),
-                Char('g') => app.move_selection_to_top(),
-                Char('u') => _ = app.undo(),
-                Char('r') => _ = app.redo(),
-                Char('G') => app.move_selection_to_bottom(),
-                Char('d') => _ = app.delete_selected_task(),
-                Char('n') => _ = app.init_insert_mode_to_insert_new_task_at_the_end(),
-                Char('o') => _ = app.init_insert_mode_to_insert_new_task_below(),
-                Char('O') => _ = app.init_insert_mode_to_insert_new_task_above(),
-                Char('e') => _ = app.init_insert_mode_to_edit_task_title(),
-                Enter | Right | Char('l') => app.open_selected_task(),
-                Esc | Left | Backspace | Char('h') => _ = app.get_back_to_parent(),
-                Char('[') => _ = app.swap_up(),
-                Char(']') => _ = app.swap_down(),
-                Up if key.modifiers.contains(KeyModifiers::ALT) => _ = app.swap_up(),
-                Down if key.modifiers.contains(KeyModifiers::ALT) => _ = app.swap_down(),
-                Up | Char('k') => app.move_selection_up(),
-                Down | Char('j') => app.move_selection_down(),
-                Tab => _ = app.update_done_state(),
-                _ => {}
-            },
+            AppState::Normal if key.kind == KeyEventKind::Press => {
+                app.status_message = None;
+
+                if let Some(flow) = handle_navigation_key(app, key) {
+                    return Ok(flow);
+                }
+
+                if let Some(edit) = editing_action(key) {
+                    match app.storage.read_only_reason() {
+                        Some(reason) => app.status_message = Some(format!("Can't edit in read-only mode, {reason}")),
+                        None => edit(app),
+                    }
+                }
+            }
             AppState::InsertTask { .. } => match key.code {
                 Esc => app.cancel_insert_mode(),
                 Enter => {
                     app.close_insert_mode_inserting_new_task();
                 }
+                Tab => _ = app.complete_tag(),
+                Up => app.move_tag_suggestion(-1),
+                Down => app.move_tag_suggestion(1),
                 _ => {
                     app.text_area.input(key);
+                    app.tag_suggestion = 0;
                 }
             },
             AppState::EditTask { .. } => match key.code {
@@ -103,13 +153,129 @@ fn handle_input(app: &mut App) -> Result<ControlFlow<()>> {
                 Enter => {
                     app.close_insert_mode_updating_task_title();
                 }
+                Tab => _ = app.complete_tag(),
+                Up => app.move_tag_suggestion(-1),
+                Down => app.move_tag_suggestion(1),
+                _ => {
+                    app.text_area.input(key);
+                    app.tag_suggestion = 0;
+                }
+            },
+            AppState::EditDate { .. } => match key.code {
+                Esc => app.cancel_insert_mode(),
+                Enter => app.close_date_editor(),
                 _ => {
                     app.text_area.input(key);
                 }
             },
+            AppState::EditNotes { .. } => match key.code {
+                Esc => app.close_notes_editor(true),
+                Char('s') if key.modifiers.contains(KeyModifiers::CONTROL) => app.close_notes_editor(false),
+                _ => {
+                    app.text_area.input(key);
+                }
+            },
+            AppState::PickStatus { .. } if key.kind == KeyEventKind::Press => match key.code {
+                Esc | Char('q') => app.close_status_picker(None),
+                Char(key) => {
+                    if let Some(status) = TaskStatus::ALL
+                        .into_iter()
+                        .find(|&status| render::status_key(status) == key)
+                    {
+                        app.close_status_picker(Some(status));
+                    }
+                }
+                _ => {}
+            },
+            AppState::Tags { .. } if key.kind == KeyEventKind::Press => match key.code {
+                Esc | Left | Char('h') | Char('q') => app.close_tags(),
+                Up | Char('k') => app.move_tags_selection(-1),
+                Down | Char('j') => app.move_tags_selection(1),
+                Char('g') => app.move_tags_selection(isize::MIN),
+                Char('G') => app.move_tags_selection(isize::MAX),
+                Enter | Right | Char('l') => _ = app.open_selected_tag_item(),
+                _ => {}
+            },
+            AppState::Agenda { .. } if key.kind == KeyEventKind::Press => match key.code {
+                Esc | Char('q') | Char('A') => app.close_agenda(),
+                Up | Char('k') => app.move_agenda_selection(-1),
+                Down | Char('j') => app.move_agenda_selection(1),
+                Char('g') => app.move_agenda_selection(isize::MIN),
+                Char('G') => app.move_agenda_selection(isize::MAX),
+                Enter => _ = app.jump_to_selected_agenda_task(),
+                _ => {}
+            },
+            AppState::History { .. } if key.kind == KeyEventKind::Press => match key.code {
+                Esc | Char('q') | Char('U') => app.close_history(),
+                Up | Char('k') => app.move_history_selection(-1),
+                Down | Char('j') => app.move_history_selection(1),
+                Char('g') => app.move_history_selection(isize::MIN),
+                Char('G') => app.move_history_selection(isize::MAX),
+                Enter => match app.storage.read_only_reason() {
+                    Some(reason) => app.status_message = Some(format!("Can't edit in read-only mode, {reason}")),
+                    None => _ = app.jump_to_selected_history_node(),
+                },
+                _ => {}
+            },
             _ => {}
         }
     }
 
     Ok(ControlFlow::Continue(()))
 }
+
+/// Handles keys that don't change the tasks, returns `None` for other keys.
+fn handle_navigation_key(app: &mut App, key: KeyEvent) -> Option<ControlFlow<()>> {
+    let is_alt_pressed = key.modifiers.contains(KeyModifiers::ALT);
+    let is_control_pressed = key.modifiers.contains(KeyModifiers::CONTROL);
+
+    match key.code {
+        Char('q') => return Some(ControlFlow::Break(())),
+        Char('o') if is_control_pressed => _ = app.jump_back(),
+        Char('n') if is_control_pressed => _ = app.jump_forward(),
+        Char('g') => app.move_selection_to_top(),
+        Char('G') => app.move_selection_to_bottom(),
+        Char('y') => _ = app.yank_selected_task(),
+        Char('U') => app.open_history(),
+        Char('#') => app.open_tags(),
+        Char('A') => app.open_agenda(),
+        Enter | Right | Char('l') => app.open_selected_task(),
+        Esc | Left | Backspace | Char('h') => _ = app.get_back_to_parent(),
+        Up | Char('k') if !is_alt_pressed => app.move_selection_up(),
+        Down | Char('j') if !is_alt_pressed => app.move_selection_down(),
+        _ => return None,
+    }
+
+    Some(ControlFlow::Continue(()))
+}
+
+/// The action for keys that change the tasks, these are disabled in read-only mode.
+fn editing_action(key: KeyEvent) -> Option<fn(&mut App)> {
+    let action: fn(&mut App) = match key.code {
+        Char('u') => |app| _ = app.undo(),
+        Char('r') => |app| _ = app.redo(),
+        Char('d') => |app| _ = app.delete_selected_task(),
+        Char('p') => |app| _ = app.paste_below(),
+        Char('P') => |app| _ = app.paste_above(),
+        Char('a') => |app| _ = app.paste_as_child(),
+        Char('n') => |app| _ = app.init_insert_mode_to_insert_new_task_at_the_end(),
+        Char('o') => |app| _ = app.init_insert_mode_to_insert_new_task_below(),
+        Char('O') => |app| _ = app.init_insert_mode_to_insert_new_task_above(),
+        Char('i') => |app| _ = app.init_insert_mode_to_push_new_task(),
+        Char('e') => |app| _ = app.init_insert_mode_to_edit_task_title(),
+        Char('N') => |app| _ = app.init_notes_editor(),
+        Char('D') => |app| _ = app.init_date_editor(DateKind::Due),
+        Char('W') => |app| _ = app.init_date_editor(DateKind::Scheduled),
+        Char('s') => |app| _ = app.cycle_selected_task_status(),
+        Char('S') => |app| _ = app.open_status_picker(),
+        Char('x') => |app| _ = app.pop_opened_task(false),
+        Char('X') => |app| _ = app.pop_opened_task(true),
+        Char('[') | Up => |app| _ = app.swap_up(),
+        Char(']') | Down => |app| _ = app.swap_down(),
+        Char('>') => |app| _ = app.indent_selected_task(),
+        Char('<') => |app| _ = app.outdent_selected_task(),
+        Tab => |app| _ = app.update_done_state(),
+        _ => return None,
+    };
+    Some(action)
+}
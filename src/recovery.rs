@@ -0,0 +1,91 @@
+//! Screen shown instead of the tree when the state file is corrupt.
+
+use std::{io, path::PathBuf};
+
+use color_eyre::{Report, Result};
+use fs_err as fs;
+use ratatui::{
+    Terminal,
+    backend::CrosstermBackend,
+    crossterm::event::{self, KeyCode::*, KeyEventKind},
+};
+
+use crate::{
+    render::render_recovery,
+    storage::{self, AppStorage, CorruptStateFile, StorageConfig},
+};
+
+pub struct RecoveryScreen {
+    pub config: StorageConfig,
+    pub error: String,
+    pub latest_backup: Option<PathBuf>,
+    /// Why the last attempt to recover failed.
+    pub message: Option<String>,
+}
+
+/// Loads the state file, letting the user pick how to recover if it's corrupt.
+///
+/// Returns `None` if the user chose to quit.
+pub fn load_or_recover(
+    config: StorageConfig,
+    terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
+) -> Result<Option<AppStorage>> {
+    match AppStorage::load_state(config.clone()) {
+        Ok(storage) => Ok(Some(storage)),
+        Err(error) if error.downcast_ref::<CorruptStateFile>().is_some() => recover(config, &error, terminal),
+        Err(error) => Err(error),
+    }
+}
+
+fn recover(
+    config: StorageConfig,
+    error: &Report,
+    terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
+) -> Result<Option<AppStorage>> {
+    let mut backups = storage::list_backups(&config.file_path)?.into_iter();
+
+    let mut screen = RecoveryScreen {
+        config,
+        error: error.to_string(),
+        latest_backup: backups.next(),
+        message: None,
+    };
+
+    loop {
+        terminal.draw(|frame| render_recovery(frame, &screen))?;
+
+        let event::Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+
+        match key.code {
+            Char('b') => {
+                let Some(backup) = screen.latest_backup.take() else {
+                    continue;
+                };
+
+                // Moved before loading, so the storage doesn't start from the stamp of the broken
+                // file and take its disappearance for a deletion outside of nest
+                let moved_to = storage::move_aside(&screen.config.file_path)?;
+                match AppStorage::load_from(&backup, screen.config.clone()) {
+                    Ok(storage) => return Ok(Some(storage)),
+                    Err(error) => {
+                        // Left where it was if the user quits instead
+                        fs::rename(&moved_to, &screen.config.file_path)?;
+                        screen.message = Some(format!("Couldn't open {}: {error}", storage::file_name(&backup)));
+                        screen.latest_backup = backups.next();
+                    }
+                }
+            }
+            Char('f') => {
+                storage::move_aside(&screen.config.file_path)?;
+                return AppStorage::load_state(screen.config).map(Some);
+            }
+            Char('q') | Esc => return Ok(None),
+            _ => {}
+        }
+    }
+}
@@ -1,12 +1,22 @@
+use std::cmp::Ordering;
+
+use chrono::{Local, NaiveDate, TimeDelta, Utc};
 use ratatui::{
     Frame,
-    layout::{Constraint, Direction, Layout, Margin, Rect},
+    layout::{Constraint, Direction, Flex, Layout, Margin, Rect},
     style::{Color, Modifier, Style, Stylize},
     text::{Line, Span},
-    widgets::{Block, BorderType, Borders, List, ListItem, ListState},
+    widgets::{Block, BorderType, Borders, Clear, List, ListItem, ListState, Paragraph, Wrap},
 };
 
-use crate::app::{App, AppState};
+use crate::{
+    app::{App, AppState},
+    dates,
+    entities::{self, Task, TaskId, TaskStatus},
+    history::{TaskChange, TaskChanges},
+    recovery::RecoveryScreen,
+    storage,
+};
 
 pub fn render_app(frame: &mut Frame, app: &mut App) {
     let stack_list = {
@@ -14,13 +24,7 @@ pub fn render_app(frame: &mut Frame, app: &mut App) {
             .find_parents_stack()
             .into_iter()
             .rev()
-            .map(|task| {
-                let mut span = Span::from(task.title.clone());
-                if task.done {
-                    span = span.add_modifier(Modifier::CROSSED_OUT).add_modifier(Modifier::DIM)
-                }
-                span
-            })
+            .map(title_spans)
             .map(Line::from)
             .map(ListItem::new);
 
@@ -38,16 +42,39 @@ pub fn render_app(frame: &mut Frame, app: &mut App) {
     let elements_list = {
         let viewed_nodes = app.find_opened_sub_tasks();
 
+        let now = Utc::now();
+        let today = dates::today();
         let elements = viewed_nodes
             .iter()
             .map(|task| {
-                let mut span = Span::from(task.title.clone());
-                if task.done {
-                    span = span.add_modifier(Modifier::CROSSED_OUT).add_modifier(Modifier::DIM)
-                }
-                span
+                // Open tasks in the middle of their workflow say where they are
+                let status = match task.status {
+                    TaskStatus::InProgress | TaskStatus::Blocked | TaskStatus::Waiting => {
+                        let label = format!(" [{}]", task.status.label());
+                        Some(Span::from(label).style(status_style(task.status)))
+                    }
+                    _ => None,
+                };
+
+                let dates = date_spans(task, today);
+
+                let age = match task.status.is_closed() {
+                    true => task
+                        .completed_at
+                        .map(|at| format!("{} {}", task.status.label(), format_age(now - at))),
+                    false => task.created_at.map(|at| format!("added {}", format_age(now - at))),
+                };
+                let age = age.map(|age| Span::from(format!("  {age}")).add_modifier(Modifier::DIM));
+                let notes_glyph = (!task.notes.is_empty()).then(|| Span::from(" ✎"));
+                Line::from_iter(
+                    title_spans(task)
+                        .into_iter()
+                        .chain(status)
+                        .chain(notes_glyph)
+                        .chain(dates)
+                        .chain(age),
+                )
             })
-            .map(Line::from)
             .map(ListItem::new);
 
         List::new(elements)
@@ -61,13 +88,25 @@ pub fn render_app(frame: &mut Frame, app: &mut App) {
             .highlight_symbol(" > ")
     };
 
-    let entire_area = frame.area().inner(Margin::new(3, 1));
+    let mut entire_area = frame.area().inner(Margin::new(3, 1));
+
+    let status_line = match (&app.status_message, app.storage.read_only_reason()) {
+        (Some(message), _) => Some(Line::from(format!(" {message}")).fg(Color::Yellow)),
+        (None, Some(reason)) => Some(Line::from(format!(" Read-only, {reason}")).add_modifier(Modifier::DIM)),
+        (None, None) => None,
+    };
+
+    if let Some(status_line) = status_line {
+        let [main_area, status_area] = Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(entire_area);
+        frame.render_widget(status_line, status_area);
+        entire_area = main_area;
+    }
 
     let elements_view_constraint = Constraint::Min(elements_list.len() as u16);
 
     let mut selected_task_state = ListState::default().with_selected(app.get_or_init_selected_position().into());
 
-    if !app.find_parents_titles().is_empty() {
+    let elements_area = if !app.find_parents_titles().is_empty() {
         let stack_view_constraint = Constraint::Length(2 + app.find_parents_titles().len() as u16);
 
         let layout = Layout::default()
@@ -76,14 +115,32 @@ pub fn render_app(frame: &mut Frame, app: &mut App) {
             .split(entire_area);
 
         frame.render_widget(stack_list, layout[0]);
-        frame.render_stateful_widget(elements_list, layout[1], &mut selected_task_state);
+        layout[1]
     } else {
         let layout = Layout::default()
             .direction(Direction::Vertical)
             .constraints([elements_view_constraint])
             .split(entire_area);
 
-        frame.render_stateful_widget(elements_list, layout[0], &mut selected_task_state);
+        layout[0]
+    };
+
+    let selected_notes = app.get_selected_task().map(|task| task.notes.clone());
+    match selected_notes.filter(|notes| !notes.is_empty()) {
+        Some(notes) => {
+            let [list_area, notes_area] =
+                Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(elements_area);
+            let notes_preview = Paragraph::new(notes).wrap(Wrap { trim: false }).block(
+                Block::default()
+                    .title(" Notes ")
+                    .borders(Borders::ALL)
+                    .border_type(BorderType::Rounded),
+            );
+
+            frame.render_stateful_widget(elements_list, list_area, &mut selected_task_state);
+            frame.render_widget(notes_preview, notes_area);
+        }
+        None => frame.render_stateful_widget(elements_list, elements_area, &mut selected_task_state),
     }
 
     if let AppState::EditTask { .. } | AppState::InsertTask { .. } = app.state {
@@ -97,6 +154,459 @@ pub fn render_app(frame: &mut Frame, app: &mut App) {
         app.text_area.set_block(popup_block);
 
         frame.render_widget(&app.text_area, area);
+        render_tag_suggestions(frame, app, area);
+    }
+
+    if let AppState::EditDate { task_id, kind } = app.state {
+        let title = match app.storage.get_task(task_id) {
+            Some(task) => format!("The {} of '{}'", kind.label(), task.title),
+            None => format!("The {}", kind.label()),
+        };
+        let popup_block = Block::default()
+            .title(title)
+            .title_bottom("e.g. fri, tomorrow, +3d or 2026-11-02, empty to clear")
+            .borders(Borders::NONE)
+            .style(Style::default().bg(Color::DarkGray));
+
+        let area = centered_rect(60, 25, frame.area());
+
+        app.text_area.set_block(popup_block);
+
+        frame.render_widget(&app.text_area, area);
+    }
+
+    if let AppState::EditNotes { task_id } = app.state {
+        let title = match app.storage.get_task(task_id) {
+            Some(task) => format!(" Notes of '{}' ", task.title),
+            None => " Notes ".to_owned(),
+        };
+        app.text_area.set_block(
+            Block::default()
+                .title(title)
+                .title_bottom(" [ctrl-s] save  [esc] cancel ")
+                .borders(Borders::ALL)
+                .border_type(BorderType::Rounded),
+        );
+
+        frame.render_widget(Clear, entire_area);
+        frame.render_widget(&app.text_area, entire_area);
+    }
+
+    if let AppState::PickStatus { task_id } = app.state {
+        render_status_picker(frame, app, task_id);
+    }
+
+    if let AppState::Tags { tag, selected } = &app.state {
+        render_tags(frame, app, tag.as_deref(), *selected);
+    }
+
+    if let AppState::Agenda { selected } = app.state {
+        render_agenda(frame, app, selected);
+    }
+
+    if let AppState::History {
+        states,
+        selected,
+        preview,
+    } = &app.state
+    {
+        render_history(frame, app, states, *selected, preview.as_ref());
+    }
+}
+
+/// The due and scheduled dates of a task, due dates of open tasks standing out once they're
+/// reached.
+fn date_spans(task: &Task, today: NaiveDate) -> Vec<Span<'static>> {
+    let due = task.due.map(|due| {
+        let style = match due.cmp(&today) {
+            _ if task.status.is_closed() => Style::new().add_modifier(Modifier::DIM),
+            Ordering::Less => Style::new().fg(Color::Red).add_modifier(Modifier::BOLD),
+            Ordering::Equal => Style::new().fg(Color::Yellow),
+            Ordering::Greater => Style::new(),
+        };
+        Span::styled(format!("  due {}", dates::format_date(due, today)), style)
+    });
+    let scheduled = task.scheduled.map(|scheduled| {
+        Span::from(format!("  scheduled {}", dates::format_date(scheduled, today))).add_modifier(Modifier::DIM)
+    });
+    due.into_iter().chain(scheduled).collect()
+}
+
+/// Every open task with a date under the group it comes up in, with the path to it.
+fn render_agenda(frame: &mut Frame, app: &App, selected: usize) {
+    let today = dates::today();
+    let agenda = app.agenda();
+
+    let mut items = Vec::new();
+    let mut selected_item = None;
+    let mut last_group = None;
+    for (position, (group, date, task)) in agenda.into_iter().enumerate() {
+        if last_group != Some(group) {
+            items.push(ListItem::new(Line::from(group.label()).add_modifier(Modifier::BOLD)));
+            last_group = Some(group);
+        }
+        if position == selected {
+            selected_item = Some(items.len());
+        }
+
+        let date = Span::from(format!("  {:<10} ", dates::format_date(date, today)));
+        let line = Line::from_iter(
+            [date, breadcrumb(app, task)]
+                .into_iter()
+                .chain(title_spans(task))
+                .chain(date_spans(task, today)),
+        );
+        items.push(ListItem::new(line));
+    }
+    if items.is_empty() {
+        items.push(ListItem::new(
+            Line::from("No open task has a date, set one with D or W").add_modifier(Modifier::DIM),
+        ));
+    }
+
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .title(" Agenda ")
+                .title_bottom(" [enter] go to task  [esc] close ")
+                .borders(Borders::ALL)
+                .border_type(BorderType::Rounded),
+        )
+        .highlight_style(Style::new().reversed());
+
+    let area = centered_rect(80, 70, frame.area());
+    frame.render_widget(Clear, area);
+    frame.render_stateful_widget(list, area, &mut ListState::default().with_selected(selected_item));
+}
+
+/// The titles of the ancestors of a task, to tell where it is in lists from across the tree.
+fn breadcrumb(app: &App, task: &Task) -> Span<'static> {
+    let parents: Vec<_> = app
+        .storage
+        .find_task_stack(task.id)
+        .into_iter()
+        .skip(1)
+        .rev()
+        .map(|parent| format!("{} › ", parent.title))
+        .collect();
+    Span::from(parents.concat()).add_modifier(Modifier::DIM)
+}
+
+/// The title of a task styled after its status, with its tags as chips.
+fn title_spans(task: &Task) -> Vec<Span<'static>> {
+    let style = status_style(task.status);
+    task.title
+        .split_inclusive(char::is_whitespace)
+        .flat_map(|piece| {
+            let word = piece.trim_end();
+            match entities::is_tag(word) {
+                true => vec![
+                    Span::styled(word.to_owned(), style.patch(tag_style(word))),
+                    Span::styled(piece[word.len()..].to_owned(), style),
+                ],
+                false => vec![Span::styled(piece.to_owned(), style)],
+            }
+        })
+        .collect()
+}
+
+/// A chip colored after the sigil of the tag.
+fn tag_style(tag: &str) -> Style {
+    let color = match tag.chars().next() {
+        Some('#') => Color::Cyan,
+        Some('@') => Color::Green,
+        _ => Color::Magenta,
+    };
+    Style::new().fg(Color::Black).bg(color)
+}
+
+/// Tags completing the one being typed, right below the title popup.
+fn render_tag_suggestions(frame: &mut Frame, app: &App, popup_area: Rect) {
+    let suggestions = app.tag_suggestions();
+    if suggestions.is_empty() {
+        return;
+    }
+
+    let items = suggestions.into_iter().map(|tag| {
+        let style = tag_style(&tag);
+        ListItem::new(Line::from(Span::styled(tag, style)))
+    });
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .title_bottom(" [tab] complete ")
+                .borders(Borders::ALL)
+                .border_type(BorderType::Rounded),
+        )
+        .highlight_symbol(" > ");
+
+    let frame_area = frame.area();
+    let area = Rect {
+        y: popup_area.bottom(),
+        height: (list.len() as u16 + 2).min(frame_area.bottom().saturating_sub(popup_area.bottom())),
+        ..popup_area
+    };
+    frame.render_widget(Clear, area);
+    frame.render_stateful_widget(
+        list,
+        area,
+        &mut ListState::default().with_selected(Some(app.tag_suggestion)),
+    );
+}
+
+/// Every tag, or every task carrying `tag` with the path to it.
+fn render_tags(frame: &mut Frame, app: &App, tag: Option<&str>, selected: usize) {
+    let (title, items): (String, Vec<_>) = match tag {
+        None => {
+            let items = app
+                .find_tags()
+                .into_iter()
+                .map(|(tag, count)| {
+                    let count = match count {
+                        1 => "  1 task".to_owned(),
+                        count => format!("  {count} tasks"),
+                    };
+                    let style = tag_style(&tag);
+                    Line::from(vec![
+                        Span::styled(tag, style),
+                        Span::from(count).add_modifier(Modifier::DIM),
+                    ])
+                })
+                .map(ListItem::new)
+                .collect();
+            (" Tags ".to_owned(), items)
+        }
+        Some(tag) => {
+            let items = app
+                .find_tagged_tasks(tag)
+                .into_iter()
+                .map(|task| Line::from_iter(std::iter::once(breadcrumb(app, task)).chain(title_spans(task))))
+                .map(ListItem::new)
+                .collect();
+            (format!(" Tasks tagged {tag} "), items)
+        }
+    };
+
+    let hint = match tag {
+        None => " [enter] show tasks  [esc] close ",
+        Some(_) => " [enter] go to task  [esc] back to tags ",
+    };
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .title(title)
+                .title_bottom(hint)
+                .borders(Borders::ALL)
+                .border_type(BorderType::Rounded),
+        )
+        .highlight_style(Style::new().reversed());
+
+    let area = centered_rect(80, 70, frame.area());
+    frame.render_widget(Clear, area);
+    frame.render_stateful_widget(list, area, &mut ListState::default().with_selected(Some(selected)));
+}
+
+fn status_style(status: TaskStatus) -> Style {
+    match status {
+        TaskStatus::Todo => Style::new(),
+        TaskStatus::InProgress => Style::new().fg(Color::Yellow).add_modifier(Modifier::BOLD),
+        TaskStatus::Blocked => Style::new().fg(Color::Red),
+        TaskStatus::Waiting => Style::new().fg(Color::Magenta).add_modifier(Modifier::ITALIC),
+        TaskStatus::Done => Style::new().add_modifier(Modifier::CROSSED_OUT | Modifier::DIM),
+        TaskStatus::Cancelled => Style::new().fg(Color::DarkGray).add_modifier(Modifier::CROSSED_OUT),
+    }
+}
+
+/// A glyph standing for a status where the task isn't styled after it.
+fn status_marker(status: TaskStatus) -> &'static str {
+    match status {
+        TaskStatus::Todo => "☐",
+        TaskStatus::InProgress => "◐",
+        TaskStatus::Blocked => "⊘",
+        TaskStatus::Waiting => "…",
+        TaskStatus::Done => "✓",
+        TaskStatus::Cancelled => "✗",
+    }
+}
+
+/// The key picking a status in the status picker.
+pub fn status_key(status: TaskStatus) -> char {
+    match status {
+        TaskStatus::Todo => 't',
+        TaskStatus::InProgress => 'p',
+        TaskStatus::Blocked => 'b',
+        TaskStatus::Waiting => 'w',
+        TaskStatus::Done => 'd',
+        TaskStatus::Cancelled => 'c',
+    }
+}
+
+/// Every status with the key picking it, the current one of the task highlighted.
+fn render_status_picker(frame: &mut Frame, app: &App, task_id: TaskId) {
+    let current_status = app.storage.get_task(task_id).map(|task| task.status);
+
+    let lines: Vec<_> = TaskStatus::ALL
+        .into_iter()
+        .map(|status| {
+            let line = Line::from(vec![
+                Span::from(format!(" [{}] ", status_key(status))),
+                Span::from(format!("{} {}", status_marker(status), status.label())).style(status_style(status)),
+            ]);
+            match Some(status) == current_status {
+                true => line.reversed(),
+                false => line,
+            }
+        })
+        .collect();
+
+    let picker = Paragraph::new(lines).block(
+        Block::default()
+            .title(" Status ")
+            .title_bottom(" [esc] cancel ")
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded),
+    );
+
+    let height = TaskStatus::ALL.len() as u16 + 2;
+    let [area] = Layout::vertical([Constraint::Length(height)])
+        .flex(Flex::Center)
+        .areas(frame.area());
+    let [area] = Layout::horizontal([Constraint::Length(28)])
+        .flex(Flex::Center)
+        .areas(area);
+    frame.render_widget(Clear, area);
+    frame.render_widget(picker, area);
+}
+
+/// The undo tree next to a preview of what going to the selected state would change.
+fn render_history(
+    frame: &mut Frame,
+    app: &App,
+    states: &[(usize, usize)],
+    selected: usize,
+    preview: Option<&TaskChanges>,
+) {
+    let current = app.history.current();
+
+    let states = states.iter().filter_map(|&(node, depth)| {
+        let history_node = app.history.node(node)?;
+        let marker = if node == current { "●" } else { "○" };
+        let changed_at = history_node.changed_at.with_timezone(&Local).format("%b %d %H:%M");
+        let line = Line::from(format!(
+            "{}{marker} {changed_at}  {}",
+            "  ".repeat(depth),
+            history_node.description
+        ));
+        Some(ListItem::new(line))
+    });
+    let states_list = List::new(states)
+        .block(
+            Block::default()
+                .title(" History ")
+                .title_bottom(" [enter] go to state  [esc] close ")
+                .borders(Borders::ALL)
+                .border_type(BorderType::Rounded),
+        )
+        .highlight_style(Style::new().reversed());
+
+    let current_tasks = app.history.current_tasks();
+    let preview_lines = match preview {
+        Some(changes) => {
+            let lines: Vec<_> = changes
+                .task_changes(current_tasks)
+                .into_iter()
+                .map(|change| match change {
+                    TaskChange::Added(task) => Line::from(format!("+ {}", task.title)).fg(Color::Green),
+                    TaskChange::Removed(task) => Line::from(format!("- {}", task.title)).fg(Color::Red),
+                    TaskChange::Edited { before, after } if before.title != after.title => {
+                        Line::from(format!("~ {} → {}", before.title, after.title)).fg(Color::Yellow)
+                    }
+                    TaskChange::Edited { before, after } if before.status != after.status => Line::from(format!(
+                        "{} {} ({})",
+                        status_marker(after.status),
+                        after.title,
+                        after.status.label()
+                    )),
+                    TaskChange::Edited { before, after } if before.notes != after.notes => {
+                        Line::from(format!("✎ {}", after.title))
+                    }
+                    TaskChange::Edited { before, after }
+                        if (before.due, before.scheduled) != (after.due, after.scheduled) =>
+                    {
+                        Line::from(format!("◷ {}", after.title))
+                    }
+                    TaskChange::Edited { after, .. } => Line::from(format!("↳ {}", after.title)).fg(Color::Blue),
+                })
+                .collect();
+            match lines.is_empty() {
+                true => vec![Line::from("Only the order of tasks changes").add_modifier(Modifier::DIM)],
+                false => lines,
+            }
+        }
+        None => vec![Line::from("The current state").add_modifier(Modifier::DIM)],
+    };
+    let preview = Paragraph::new(preview_lines).block(
+        Block::default()
+            .title(" Going there ")
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded),
+    );
+
+    let area = centered_rect(80, 70, frame.area());
+    let [states_area, preview_area] =
+        Layout::horizontal([Constraint::Percentage(55), Constraint::Percentage(45)]).areas(area);
+    frame.render_widget(Clear, area);
+    frame.render_stateful_widget(
+        states_list,
+        states_area,
+        &mut ListState::default().with_selected(Some(selected)),
+    );
+    frame.render_widget(preview, preview_area);
+}
+
+pub fn render_recovery(frame: &mut Frame, screen: &RecoveryScreen) {
+    let backup_option = match &screen.latest_backup {
+        Some(backup) => Line::from(format!(" [b] Open the latest backup, {}", storage::file_name(backup))),
+        None => Line::from(" [b] Open the latest backup, none found").add_modifier(Modifier::DIM),
+    };
+
+    let mut lines = vec![
+        Line::from(format!("Failed to read {}:", screen.config.file_path.display())),
+        Line::from(screen.error.clone()).fg(Color::Red),
+        Line::default(),
+        backup_option,
+        Line::from(" [f] Start fresh, moving the broken file aside"),
+        Line::from(" [q] Quit"),
+    ];
+
+    if let Some(message) = &screen.message {
+        lines.extend([Line::default(), Line::from(message.clone()).fg(Color::Yellow)]);
+    }
+
+    let paragraph = Paragraph::new(lines).wrap(Wrap { trim: false }).block(
+        Block::default()
+            .title(" Corrupt state file ")
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded),
+    );
+
+    frame.render_widget(paragraph, centered_rect(70, 50, frame.area()));
+}
+
+/// How long ago something happened, in the largest unit that fits, e.g. "3d ago".
+fn format_age(age: TimeDelta) -> String {
+    let units = [
+        (TimeDelta::days(365), "y"),
+        (TimeDelta::days(30), "mo"),
+        (TimeDelta::weeks(1), "w"),
+        (TimeDelta::days(1), "d"),
+        (TimeDelta::hours(1), "h"),
+        (TimeDelta::minutes(1), "m"),
+    ];
+
+    match units.iter().find(|(unit, _)| age >= *unit) {
+        Some((unit, suffix)) => format!("{}{suffix} ago", age.num_seconds() / unit.num_seconds()),
+        None => "just now".to_owned(),
     }
 }
 
@@ -0,0 +1,53 @@
+//! The `nest restore` command, lists the backups of the state file or restores one of them.
+
+use color_eyre::{
+    Result,
+    eyre::{bail, eyre},
+};
+
+use crate::storage::{self, LockAttempt, StorageConfig};
+
+pub fn run(config: &StorageConfig, backup: Option<&str>) -> Result<()> {
+    let file_path = &config.file_path;
+    let backups = storage::list_backups(file_path)?;
+
+    let Some(backup) = backup else {
+        if backups.is_empty() {
+            println!("No backups of {} found.", file_path.display());
+            return Ok(());
+        }
+
+        println!("Backups of {}, newest first:", file_path.display());
+        for (number, backup) in (1..).zip(&backups) {
+            let time = storage::backup_time(backup).map_or_else(
+                || "unknown time".to_owned(),
+                |time| time.format("%Y-%m-%d %H:%M:%S").to_string(),
+            );
+            println!("{number:>4}  {time}  {}", storage::file_name(backup));
+        }
+        println!("\nRestore one with `nest restore <number>`.");
+        return Ok(());
+    };
+
+    let selected_backup = match backup.parse::<usize>() {
+        Ok(number) => number.checked_sub(1).and_then(|index| backups.get(index)),
+        Err(_) => backups.iter().find(|path| storage::file_name(path) == backup),
+    }
+    .ok_or_else(|| eyre!("there's no backup `{backup}`, run `nest restore` to list them"))?;
+
+    let _lock = match storage::lock_state_file(file_path)? {
+        LockAttempt::Acquired(lock) => lock,
+        LockAttempt::HeldBy(pid) => bail!(
+            "nest is open in {}, close it before restoring a backup",
+            LockAttempt::describe_holder(pid)
+        ),
+    };
+
+    storage::restore_backup(config, selected_backup)?;
+    println!(
+        "Restored {} from {}, the previous contents were backed up.",
+        file_path.display(),
+        storage::file_name(selected_backup)
+    );
+    Ok(())
+}
@@ -0,0 +1,28 @@
+//! Crash-safe file writes.
+
+use std::{io::Write, path::Path};
+
+use color_eyre::Result;
+use fs_err as fs;
+
+/// Writes to a temporary file next to `path` and renames it over `path`, so a crash or a full disk
+/// mid-write leaves either the old or the new contents, never a truncated file.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let mut temp_file = fs::File::create(&temp_path)?;
+    temp_file.write_all(contents)?;
+    temp_file.sync_all()?;
+    drop(temp_file);
+
+    fs::rename(&temp_path, path)?;
+
+    // Persist the rename itself
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
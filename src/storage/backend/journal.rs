@@ -0,0 +1,309 @@
+use std::{
+    collections::HashSet,
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use color_eyre::Result;
+use fs_err::{self as fs, File, OpenOptions};
+use serde::{Deserialize, Serialize};
+
+use super::StorageBackend;
+use crate::{
+    entities::TaskId,
+    storage::{AppStorage, CorruptStateFile, StateFile, atomic, journal::JournalEntry, schema, view::ViewStorage},
+};
+
+/// Operations are appended until they take this many bytes, or as many as the snapshot if it's
+/// bigger, then everything is compacted into a new snapshot.
+const MIN_COMPACTION_SIZE: u64 = 64 * 1024;
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Line {
+    View { view: ViewStorage },
+    Entry(JournalEntry),
+}
+
+/// The state as a snapshot on the first line of the file, followed by the operations done since,
+/// one per line, so a change only appends a line.
+///
+/// Operations are moved to `<state file name>.audit` when compacted, keeping a trail of every
+/// change.
+pub struct JournalBackend {
+    file_path: PathBuf,
+    /// Length of the first line of the file, `None` until it's read.
+    snapshot_len: Option<u64>,
+    /// What the file adds up to, the operations appended are redone on it to check they capture
+    /// everything that changed, `None` until it's read or written.
+    replayed: Option<AppStorage>,
+}
+
+impl JournalBackend {
+    pub fn new(file_path: &Path) -> Self {
+        Self {
+            file_path: file_path.to_owned(),
+            snapshot_len: None,
+            replayed: None,
+        }
+    }
+
+    fn audit_path(&self) -> PathBuf {
+        let mut audit_name = self.file_path.file_name().unwrap_or_default().to_os_string();
+        audit_name.push(".audit");
+        self.file_path.with_file_name(audit_name)
+    }
+
+    fn snapshot_len(&mut self) -> Result<u64> {
+        if let Some(snapshot_len) = self.snapshot_len {
+            return Ok(snapshot_len);
+        }
+
+        let mut snapshot = String::new();
+        BufReader::new(File::open(&self.file_path)?).read_line(&mut snapshot)?;
+        self.snapshot_len = Some(snapshot.len() as u64);
+        Ok(snapshot.len() as u64)
+    }
+
+    /// Whether new lines can be appended, the last line may be cut short if nest crashed while
+    /// writing it.
+    fn ends_with_newline(&self) -> Result<bool> {
+        if !self.file_path.try_exists()? {
+            return Ok(false);
+        }
+
+        let mut file = File::open(&self.file_path)?;
+        if file.metadata()?.len() == 0 {
+            return Ok(false);
+        }
+
+        let mut last_byte = [0];
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last_byte)?;
+        Ok(last_byte == *b"\n")
+    }
+
+    fn is_due_for_compaction(&mut self) -> Result<bool> {
+        let snapshot_len = self.snapshot_len()?;
+        let journal_len = fs::metadata(&self.file_path)?.len().saturating_sub(snapshot_len);
+        Ok(journal_len > snapshot_len.max(MIN_COMPACTION_SIZE))
+    }
+
+    /// Writes `state` as the new snapshot, moving the operations in the file and the ones in
+    /// `journal` to the audit trail.
+    ///
+    /// If nest crashes between both writes, the same operations are moved again on the next one.
+    fn compact(&mut self, state: &StateFile, journal: &[JournalEntry]) -> Result<()> {
+        let mut audit = String::new();
+        if self.file_path.try_exists()? {
+            let contents = fs::read_to_string(&self.file_path)?;
+            for line in contents.lines().skip(1) {
+                if let Ok(Line::Entry(_)) = serde_json::from_str(line) {
+                    audit.push_str(line);
+                    audit.push('\n');
+                }
+            }
+        }
+        for entry in journal {
+            audit.push_str(&serde_json::to_string(entry)?);
+            audit.push('\n');
+        }
+
+        if !audit.is_empty() {
+            let mut audit_file = OpenOptions::new().create(true).append(true).open(self.audit_path())?;
+            audit_file.write_all(audit.as_bytes())?;
+            audit_file.sync_data()?;
+        }
+
+        let snapshot = serde_json::to_string(state)? + "\n";
+        atomic::write_atomically(&self.file_path, snapshot.as_bytes())?;
+        self.snapshot_len = Some(snapshot.len() as u64);
+        self.replayed = Some(AppStorage::for_replay(state.view.clone(), (*state.tasks).clone()));
+        Ok(())
+    }
+
+    /// What the file adds up to, `None` if it can't be read.
+    fn take_replayed(&mut self) -> Option<AppStorage> {
+        if let Some(replayed) = self.replayed.take() {
+            return Some(replayed);
+        }
+        let (state_file, _) = self.load().ok()??;
+        Some(AppStorage::for_replay(
+            state_file.view,
+            Arc::unwrap_or_clone(state_file.tasks),
+        ))
+    }
+}
+
+impl StorageBackend for JournalBackend {
+    fn load(&mut self) -> Result<Option<(StateFile, u64)>> {
+        if !self.file_path.try_exists()? {
+            return Ok(None);
+        }
+
+        let contents = String::from_utf8(fs::read(&self.file_path)?).map_err(CorruptStateFile::new)?;
+        let mut lines = contents.split_inclusive('\n');
+        let Some(snapshot) = lines.next() else {
+            return Ok(None);
+        };
+
+        let (state_file, version) = schema::parse_state_file(snapshot.as_bytes())?;
+        let mut storage = AppStorage::from_state_file(state_file);
+
+        for (line_number, line) in (2..).zip(lines) {
+            match serde_json::from_str(line) {
+                Ok(Line::View { view }) => storage.view = view,
+                Ok(Line::Entry(entry)) => storage.apply(&entry).ok_or_else(|| {
+                    CorruptStateFile::new(format!(
+                        "the operation on line {line_number} doesn't apply to the tasks"
+                    ))
+                })?,
+                // Left cut short by a crash, it's compacted away on the next save
+                Err(_) if !line.ends_with('\n') => break,
+                Err(error) => return Err(CorruptStateFile::new(error).into()),
+            }
+        }
+
+        self.snapshot_len = Some(snapshot.len() as u64);
+        let state_file = StateFile {
+            version: schema::CURRENT_VERSION,
+            view: storage.view,
+            tasks: Arc::new(storage.tasks),
+        };
+        Ok(Some((state_file, version)))
+    }
+
+    fn save(
+        &mut self,
+        state: &StateFile,
+        changed_ids: Option<&HashSet<TaskId>>,
+        journal: &[JournalEntry],
+    ) -> Result<()> {
+        let Some(changed_ids) = changed_ids else {
+            return self.compact(state, journal);
+        };
+        if !self.ends_with_newline()? || self.is_due_for_compaction()? {
+            return self.compact(state, journal);
+        }
+        let Some(mut replayed) = self.take_replayed() else {
+            return self.compact(state, journal);
+        };
+        let view_changed = serde_json::to_value(&replayed.view)? != serde_json::to_value(&state.view)?;
+        if !replays_to(&mut replayed, journal, changed_ids, state) {
+            return self.compact(state, journal);
+        }
+
+        let mut lines = String::new();
+        for entry in journal {
+            lines.push_str(&serde_json::to_string(entry)?);
+            lines.push('\n');
+        }
+        if view_changed {
+            let view = Line::View {
+                view: state.view.clone(),
+            };
+            lines.push_str(&serde_json::to_string(&view)?);
+            lines.push('\n');
+        }
+
+        let mut file = OpenOptions::new().append(true).open(&self.file_path)?;
+        file.write_all(lines.as_bytes())?;
+        file.sync_data()?;
+        replayed.view = state.view.clone();
+        self.replayed = Some(replayed);
+        Ok(())
+    }
+
+    fn close(&mut self, saved: &StateFile) -> Result<()> {
+        // Start the next session from a snapshot, so the operations never need reading by a
+        // version of nest that saves tasks differently
+        if self.file_path.try_exists()? && fs::metadata(&self.file_path)?.len() > self.snapshot_len()? {
+            self.compact(saved, &[])?;
+        }
+        Ok(())
+    }
+}
+
+/// Redoes the operations in `journal` on `replayed`, whether that gives `state`, otherwise they
+/// don't capture everything that changed.
+///
+/// Only the tasks in `changed_ids` and the ones the operations touched are compared, the others
+/// were left as they were on both sides.
+fn replays_to(
+    replayed: &mut AppStorage,
+    journal: &[JournalEntry],
+    changed_ids: &HashSet<TaskId>,
+    state: &StateFile,
+) -> bool {
+    if !journal.iter().all(|entry| replayed.apply(entry).is_some()) {
+        return false;
+    }
+
+    let mut touched_ids = journal
+        .iter()
+        .flat_map(|entry| entry.operation.task_ids())
+        .chain(changed_ids.iter().copied());
+    replayed.tasks.len() == state.tasks.len()
+        && touched_ids.all(|id| {
+            let as_replayed = replayed.tasks.get_full(&id).map(|(index, _, task)| (index, task));
+            as_replayed == state.tasks.get_full(&id).map(|(index, _, task)| (index, task))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{history::TaskChanges, storage::schema::tests::ulids};
+
+    #[test]
+    fn reports_malformed_operations_as_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("state.jsonl");
+        let snapshot = serde_json::to_string(&StateFile::default()).unwrap();
+        std::fs::write(&file_path, format!("{snapshot}\n{{\"op\": \"teleport\"}}\n")).unwrap();
+
+        let loaded = JournalBackend::new(&file_path).load();
+
+        assert!(loaded.unwrap_err().downcast_ref::<CorruptStateFile>().is_some());
+    }
+
+    #[test]
+    fn appends_undo_as_the_tasks_put_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("state.jsonl");
+
+        let (state, _) = schema::parse_state_file(include_bytes!("../../../tests/fixtures/state_v6.json")).unwrap();
+        let mut backend = JournalBackend::new(&file_path);
+        backend.save(&state, None, &[]).unwrap();
+
+        // Rename two tasks, then undo the second rename
+        let [work, _, call, _] = ulids();
+        let mut storage = AppStorage::from_state_file(state.clone());
+        storage.update_task_title(work, "Office".to_owned());
+        let renamed_work = storage.tasks.clone();
+        storage.update_task_title(call, "Call Alice".to_owned());
+        storage.restore_tasks(TaskChanges::between(&storage.tasks, &renamed_work));
+
+        let changes = TaskChanges::between(&state.tasks, &storage.tasks);
+        let new_state = StateFile {
+            tasks: Arc::new(storage.tasks.clone()),
+            ..state
+        };
+        let journal = std::mem::take(&mut storage.journal);
+        backend
+            .save(&new_state, Some(&changes.ids().collect()), &journal)
+            .unwrap();
+
+        let contents = fs::read_to_string(&file_path).unwrap();
+        let loaded = JournalBackend::new(&file_path).load();
+
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(lines.len(), 4, "the operations are appended after the snapshot");
+        assert!(lines[3].contains(r#""op":"restore""#));
+        let (loaded, _) = loaded.unwrap().unwrap();
+        assert!(loaded.tasks.iter().eq(new_state.tasks.iter()));
+        assert_eq!(loaded.tasks[&work].title, "Office");
+        assert_eq!(loaded.tasks[&call].title, "Call Bob");
+    }
+}
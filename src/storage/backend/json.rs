@@ -0,0 +1,64 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::Result;
+use fs_err as fs;
+
+use super::StorageBackend;
+use crate::{
+    entities::TaskId,
+    storage::{StateFile, atomic, journal::JournalEntry, schema},
+};
+
+/// The state as a single pretty-printed JSON file, easy to read and to sync between machines.
+pub struct JsonBackend {
+    file_path: PathBuf,
+}
+
+impl JsonBackend {
+    pub fn new(file_path: &Path) -> Self {
+        Self {
+            file_path: file_path.to_owned(),
+        }
+    }
+}
+
+impl StorageBackend for JsonBackend {
+    fn load(&mut self) -> Result<Option<(StateFile, u64)>> {
+        if !self.file_path.try_exists()? {
+            return Ok(None);
+        }
+
+        // Read as bytes, so a file that isn't even text is reported as corrupt too
+        schema::parse_state_file(&fs::read(&self.file_path)?).map(Some)
+    }
+
+    fn save(
+        &mut self,
+        state: &StateFile,
+        _changed_ids: Option<&HashSet<TaskId>>,
+        _journal: &[JournalEntry],
+    ) -> Result<()> {
+        let json = serde_json::to_string_pretty(state)?;
+        atomic::write_atomically(&self.file_path, json.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::CorruptStateFile;
+
+    #[test]
+    fn reports_binary_garbage_as_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("state.json");
+        std::fs::write(&file_path, [0xff, 0xfe, 0x00, 0x7b, 0x80]).unwrap();
+
+        let loaded = JsonBackend::new(&file_path).load();
+
+        assert!(loaded.unwrap_err().downcast_ref::<CorruptStateFile>().is_some());
+    }
+}
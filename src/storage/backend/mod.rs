@@ -0,0 +1,98 @@
+//! Where the state is persisted, a JSON file by default, or an SQLite database or a journal for big trees.
+
+use std::{collections::HashSet, path::Path};
+
+use color_eyre::Result;
+
+use super::{StateFile, journal::JournalEntry};
+use crate::entities::TaskId;
+
+mod journal;
+mod json;
+mod sqlite;
+
+pub use journal::JournalBackend;
+pub use json::JsonBackend;
+pub use sqlite::SqliteBackend;
+
+/// A way of saving and loading the state, backups and locking work on its file as a whole.
+pub trait StorageBackend: Send {
+    /// Reads what was saved together with the version of the layout it was saved in, upgraded to
+    /// the current one, `None` if nothing was saved yet.
+    ///
+    /// Fails with a `CorruptStateFile` if what's saved can't be read, other errors are about
+    /// getting to it, e.g. the file being locked or unreadable.
+    fn load(&mut self) -> Result<Option<(StateFile, u64)>>;
+
+    /// Saves `state`, `changed_ids` are the tasks that changed since the last save through this
+    /// backend and `journal` the operations that changed them, so only the differences need
+    /// writing.
+    ///
+    /// `changed_ids` is `None` when what's saved isn't known, e.g. it was written by someone else
+    /// or in another version, then everything is written.
+    ///
+    /// Must leave the file consistent between calls, so it can be copied for backups.
+    fn save(
+        &mut self,
+        state: &StateFile,
+        changed_ids: Option<&HashSet<TaskId>>,
+        journal: &[JournalEntry],
+    ) -> Result<()>;
+
+    /// Called when the writer stops with what's saved, for backends that want to tidy up.
+    fn close(&mut self, _saved: &StateFile) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    /// The whole state in one JSON file, rewritten on every save.
+    #[default]
+    Json,
+    /// An SQLite database with a row per task, only changed rows are written.
+    Sqlite,
+    /// A snapshot followed by the operations done since, appended on every save.
+    Journal,
+}
+
+impl BackendKind {
+    pub const ALL: [BackendKind; 3] = [BackendKind::Json, BackendKind::Sqlite, BackendKind::Journal];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(BackendKind::Json),
+            "sqlite" => Some(BackendKind::Sqlite),
+            "journal" => Some(BackendKind::Journal),
+            _ => None,
+        }
+    }
+
+    /// Guesses from the extension of the state file, `.db`, `.sqlite` and `.sqlite3` are SQLite
+    /// and `.jsonl` is a journal.
+    pub fn from_path(file_path: &Path) -> Self {
+        let extension = file_path.extension().map(|extension| extension.to_string_lossy());
+        match extension.as_deref() {
+            Some("db" | "sqlite" | "sqlite3") => BackendKind::Sqlite,
+            Some("jsonl") => BackendKind::Journal,
+            _ => BackendKind::Json,
+        }
+    }
+
+    /// Extension of state files that don't have a path picked by the user.
+    pub fn extension(self) -> &'static str {
+        match self {
+            BackendKind::Json => "json",
+            BackendKind::Sqlite => "db",
+            BackendKind::Journal => "jsonl",
+        }
+    }
+
+    pub fn open(self, file_path: &Path) -> Box<dyn StorageBackend> {
+        match self {
+            BackendKind::Json => Box::new(JsonBackend::new(file_path)),
+            BackendKind::Sqlite => Box::new(SqliteBackend::new(file_path)),
+            BackendKind::Journal => Box::new(JournalBackend::new(file_path)),
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use color_eyre::{Report, Result};
+use indexmap::IndexMap;
+use rusqlite::{Connection, ErrorCode, OptionalExtension, params};
+use serde_json::{Map, Value};
+
+use super::StorageBackend;
+use crate::{
+    entities::{Task, TaskId},
+    storage::{CorruptStateFile, StateFile, journal::JournalEntry, schema},
+};
+
+/// The tasks are kept as JSON, so they go through the same migrations as the JSON backend.
+const CREATE_TABLES: &str = "
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS tasks (
+        id TEXT PRIMARY KEY,
+        -- Order in the task map, root tasks are shown in this order
+        position INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+";
+
+/// Space left between the positions of tasks written together, so a task put between two others
+/// gets a position without moving them.
+const POSITION_GAP: i64 = 1 << 16;
+
+/// The state in an SQLite database with a row per task, so saving a change to a big tree only
+/// writes the rows that changed.
+pub struct SqliteBackend {
+    file_path: PathBuf,
+    /// Opened on first use, so a missing database is only created when saving.
+    connection: Option<Connection>,
+    /// Positions of the saved tasks, read from the database the first time only some rows are written.
+    positions: Option<HashMap<TaskId, i64>>,
+}
+
+impl SqliteBackend {
+    pub fn new(file_path: &Path) -> Self {
+        Self {
+            file_path: file_path.to_owned(),
+            connection: None,
+            positions: None,
+        }
+    }
+
+    /// `None` if the tables weren't created yet.
+    fn read_positions(&mut self) -> Result<Option<HashMap<TaskId, i64>>> {
+        let connection = self.connection()?;
+        if !has_tables(connection)? {
+            return Ok(None);
+        }
+
+        let mut statement = connection.prepare("SELECT id, position FROM tasks")?;
+        let mut rows = statement.query([])?;
+        let mut positions = HashMap::new();
+        while let Some(row) = rows.next()? {
+            let id = serde_json::from_value(Value::String(row.get(0)?))?;
+            positions.insert(id, row.get(1)?);
+        }
+        Ok(Some(positions))
+    }
+
+    fn connection(&mut self) -> Result<&mut Connection> {
+        if self.connection.is_none() {
+            let connection = Connection::open(&self.file_path)?;
+            // Another instance may be reading the database while reloading external changes
+            connection.busy_timeout(Duration::from_secs(5))?;
+            self.connection = Some(connection);
+        }
+        Ok(self.connection.as_mut().expect("the connection was just opened"))
+    }
+}
+
+/// Gives the tasks in `changed_ids` positions between the ones of the tasks around them, the
+/// others keep theirs, as they're still in the same order.
+///
+/// Returns `None` if there's no room left between two positions, or one isn't known, and every
+/// task needs a new one.
+fn place_changed(
+    positions: &mut HashMap<TaskId, i64>,
+    tasks: &IndexMap<TaskId, Task>,
+    changed_ids: &HashSet<TaskId>,
+) -> Option<()> {
+    let mut indexes = vec![];
+    for id in changed_ids {
+        match tasks.get_index_of(id) {
+            Some(index) => indexes.push(index),
+            None => _ = positions.remove(id),
+        }
+    }
+    indexes.sort_unstable();
+
+    // Runs of tasks next to each other are spread evenly between the tasks around them
+    for run in indexes.chunk_by(|index, next| index + 1 == *next) {
+        let position_at = |index: usize| positions.get(tasks.get_index(index)?.0).copied();
+        let before = match run[0] {
+            0 => None,
+            first => Some(position_at(first - 1)?),
+        };
+        let after = match run[run.len() - 1] + 1 {
+            next if next == tasks.len() => None,
+            next => Some(position_at(next)?),
+        };
+
+        let count = run.len() as i64;
+        let (start, step) = match (before, after) {
+            (None, None) => (0, POSITION_GAP),
+            (Some(before), None) => (before.checked_add(POSITION_GAP)?, POSITION_GAP),
+            (None, Some(after)) => (after.checked_sub(POSITION_GAP.checked_mul(count)?)?, POSITION_GAP),
+            (Some(before), Some(after)) => {
+                let step = (after - before) / (count + 1);
+                (before + step, step)
+            }
+        };
+        if step == 0 {
+            return None;
+        }
+
+        for (offset, &index) in run.iter().enumerate() {
+            let (id, _) = tasks.get_index(index)?;
+            positions.insert(*id, start + step * offset as i64);
+        }
+    }
+    Some(())
+}
+
+fn has_tables(connection: &Connection) -> rusqlite::Result<bool> {
+    let count = connection.query_row(
+        "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name IN ('meta', 'tasks')",
+        [],
+        |row| row.get::<_, u32>(0),
+    )?;
+    Ok(count == 2)
+}
+
+/// What's saved, as the JSON it was saved as.
+struct SavedRows {
+    version: String,
+    view: String,
+    /// The id and data of every task, in order.
+    tasks: Vec<(String, String)>,
+}
+
+/// `None` if nothing was saved yet.
+fn read_rows(connection: &Connection) -> rusqlite::Result<Option<SavedRows>> {
+    if !has_tables(connection)? {
+        return Ok(None);
+    }
+
+    let get_meta = |key: &str| {
+        connection
+            .query_row("SELECT value FROM meta WHERE key = ?1", [key], |row| row.get(0))
+            .optional()
+    };
+    let (Some(version), Some(view)) = (get_meta("version")?, get_meta("view")?) else {
+        return Ok(None);
+    };
+
+    // Ids were integers before version 2
+    let mut statement = connection.prepare("SELECT CAST(id AS TEXT), data FROM tasks ORDER BY position")?;
+    let tasks = statement
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(Some(SavedRows { version, view, tasks }))
+}
+
+/// SQLite failing on what's in the file is corruption, other failures, e.g. it being locked for
+/// too long, aren't.
+fn load_error(error: rusqlite::Error) -> Report {
+    match error.sqlite_error_code() {
+        Some(ErrorCode::NotADatabase | ErrorCode::DatabaseCorrupt) | None => CorruptStateFile::new(error).into(),
+        Some(_) => error.into(),
+    }
+}
+
+impl StorageBackend for SqliteBackend {
+    fn load(&mut self) -> Result<Option<(StateFile, u64)>> {
+        if !self.file_path.try_exists()? {
+            return Ok(None);
+        }
+        let connection = self.connection()?;
+        let Some(SavedRows {
+            version,
+            view,
+            tasks: rows,
+        }) = read_rows(connection).map_err(load_error)?
+        else {
+            return Ok(None);
+        };
+
+        let mut tasks = Map::new();
+        for (id, data) in rows {
+            tasks.insert(id, serde_json::from_str(&data).map_err(CorruptStateFile::new)?);
+        }
+
+        // Put the rows back together as a state file, so it's migrated like one
+        let document = Map::from_iter([
+            (
+                "version".to_owned(),
+                serde_json::from_str::<Value>(&version).map_err(CorruptStateFile::new)?,
+            ),
+            (
+                "view".to_owned(),
+                serde_json::from_str(&view).map_err(CorruptStateFile::new)?,
+            ),
+            ("tasks".to_owned(), Value::Object(tasks)),
+        ]);
+        schema::migrate_document(Value::Object(document)).map(Some)
+    }
+
+    fn save(
+        &mut self,
+        state: &StateFile,
+        changed_ids: Option<&HashSet<TaskId>>,
+        _journal: &[JournalEntry],
+    ) -> Result<()> {
+        // Only the changed rows are written if there's room for them between the others
+        let placed = match changed_ids {
+            Some(changed_ids) => {
+                let positions = match self.positions.take() {
+                    Some(positions) => Some(positions),
+                    None => self.read_positions()?,
+                };
+                positions.and_then(|mut positions| {
+                    place_changed(&mut positions, &state.tasks, changed_ids).map(|()| (changed_ids, positions))
+                })
+            }
+            None => None,
+        };
+        // Rows written by someone else, or laid out for another version, are all written again
+        let (changed_ids, positions) = match placed {
+            Some((changed_ids, positions)) => (Some(changed_ids), positions),
+            None => {
+                let positions = (0..)
+                    .step_by(POSITION_GAP as usize)
+                    .zip(state.tasks.keys())
+                    .map(|(position, id)| (*id, position))
+                    .collect();
+                (None, positions)
+            }
+        };
+
+        let transaction = self.connection()?.transaction()?;
+        if changed_ids.is_none() {
+            transaction.execute("DROP TABLE IF EXISTS tasks", [])?;
+        }
+        transaction.execute_batch(CREATE_TABLES)?;
+
+        let upsert_meta = "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)";
+        transaction.execute(upsert_meta, params!["version", state.version.to_string()])?;
+        transaction.execute(upsert_meta, params!["view", serde_json::to_string(&state.view)?])?;
+
+        {
+            let mut upsert_task =
+                transaction.prepare("INSERT OR REPLACE INTO tasks (id, position, data) VALUES (?1, ?2, ?3)")?;
+            let mut delete_task = transaction.prepare("DELETE FROM tasks WHERE id = ?1")?;
+            let ids: Box<dyn Iterator<Item = &TaskId>> = match changed_ids {
+                Some(changed_ids) => Box::new(changed_ids.iter()),
+                None => Box::new(state.tasks.keys()),
+            };
+            for id in ids {
+                match (state.tasks.get(id), positions.get(id)) {
+                    (Some(task), Some(position)) => {
+                        upsert_task.execute(params![id.to_string(), position, serde_json::to_string(task)?])?;
+                    }
+                    _ => _ = delete_task.execute([id.to_string()])?,
+                }
+            }
+        }
+
+        transaction.commit()?;
+        self.positions = Some(positions);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::storage::schema::tests::{assert_fixture_tree, legacy_ids};
+
+    fn read_positions(file_path: &Path) -> Vec<(String, i64)> {
+        let connection = Connection::open(file_path).unwrap();
+        let mut statement = connection
+            .prepare("SELECT id, position FROM tasks ORDER BY position")
+            .unwrap();
+        statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn reports_garbage_as_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("state.db");
+        std::fs::write(&file_path, "not a database, ".repeat(64)).unwrap();
+
+        let loaded = SqliteBackend::new(&file_path).load();
+
+        assert!(loaded.unwrap_err().downcast_ref::<CorruptStateFile>().is_some());
+    }
+
+    #[test]
+    fn writes_only_the_changed_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("state.db");
+
+        let (mut state, _) = schema::parse_state_file(include_bytes!("../../../tests/fixtures/state_v6.json")).unwrap();
+        let mut backend = SqliteBackend::new(&file_path);
+        backend.save(&state, None, &[]).unwrap();
+        let positions = read_positions(&file_path);
+
+        // A new first root task goes before the others without moving them
+        let tasks = Arc::make_mut(&mut state.tasks);
+        let mut task = tasks[0].clone();
+        task.id = TaskId::new();
+        task.title = "Errands".to_owned();
+        task.children.clear();
+        tasks.shift_insert(0, task.id, task.clone());
+        backend.save(&state, Some(&HashSet::from([task.id])), &[]).unwrap();
+
+        let new_positions = read_positions(&file_path);
+        assert_eq!(new_positions[0].0, task.id.to_string());
+        assert_eq!(new_positions[1..], positions);
+
+        let loaded = SqliteBackend::new(&file_path).load();
+        let (loaded, _) = loaded.unwrap().unwrap();
+        assert!(loaded.tasks.iter().eq(state.tasks.iter()));
+    }
+
+    #[test]
+    fn migrates_rows_with_integer_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("state.db");
+
+        // Lay out the v1 fixture the way the backend saved it before ids were ULIDs
+        let fixture: Value = serde_json::from_str(include_str!("../../../tests/fixtures/state_v1.json")).unwrap();
+        let connection = Connection::open(&file_path).unwrap();
+        connection.execute_batch(CREATE_TABLES).unwrap();
+        connection
+            .execute(
+                "INSERT INTO meta (key, value) VALUES ('version', ?1)",
+                [fixture["version"].to_string()],
+            )
+            .unwrap();
+        connection
+            .execute(
+                "INSERT INTO meta (key, value) VALUES ('view', ?1)",
+                [fixture["view"].to_string()],
+            )
+            .unwrap();
+        let tasks = fixture["tasks"].as_object().unwrap();
+        for (position, (id, task)) in tasks.iter().enumerate() {
+            connection
+                .execute(
+                    "INSERT INTO tasks (id, position, data) VALUES (?1, ?2, ?3)",
+                    params![id.parse::<i64>().unwrap(), position as i64, task.to_string()],
+                )
+                .unwrap();
+        }
+        drop(connection);
+
+        let loaded = SqliteBackend::new(&file_path).load();
+
+        let (state, version) = loaded.unwrap().unwrap();
+        assert_eq!(version, 1);
+        assert_fixture_tree(&state, legacy_ids());
+    }
+}
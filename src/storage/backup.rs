@@ -0,0 +1,123 @@
+//! Copies of the state file kept next to it.
+
+use std::path::{Path, PathBuf};
+
+use chrono::{Local, NaiveDateTime};
+use color_eyre::{Result, eyre::bail};
+use fs_err as fs;
+
+use super::{StorageConfig, atomic::write_atomically};
+
+/// Sortable timestamp used in the names of backups and of files moved aside.
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%S";
+
+const BACKUP_EXTENSION: &str = "bak";
+
+/// Backups of the state file, newest first.
+///
+/// They're named `<state file name>.<timestamp>.bak`, backups taken before migrations aren't
+/// included.
+pub fn list_backups(file_path: &Path) -> Result<Vec<PathBuf>> {
+    let (Some(dir), Some(file_name)) = (parent_dir(file_path), file_path.file_name()) else {
+        return Ok(vec![]);
+    };
+    let prefix = format!("{}.", file_name.to_string_lossy());
+    let suffix = format!(".{BACKUP_EXTENSION}");
+
+    if !dir.try_exists()? {
+        return Ok(vec![]);
+    }
+
+    let mut backups = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .map(|name| name.to_string_lossy())
+                .is_some_and(|name| name.starts_with(&prefix) && name.ends_with(&suffix))
+                && backup_time(path).is_some()
+        })
+        .collect::<Vec<_>>();
+
+    backups.sort_unstable_by(|a, b| b.file_name().cmp(&a.file_name()));
+    Ok(backups)
+}
+
+/// Copies the state file to a new backup, then deletes the oldest ones beyond `backups_to_keep`.
+///
+/// Does nothing if the file doesn't exist yet or is identical to the newest backup.
+pub fn create_backup(file_path: &Path, backups_to_keep: usize) -> Result<()> {
+    if backups_to_keep == 0 || !file_path.try_exists()? {
+        return Ok(());
+    }
+
+    let contents = fs::read(file_path)?;
+    let backups = list_backups(file_path)?;
+
+    let is_already_backed_up = match backups.first() {
+        Some(newest_backup) => fs::read(newest_backup)? == contents,
+        None => false,
+    };
+
+    if !is_already_backed_up {
+        let mut backup_name = file_path.file_name().unwrap_or_default().to_os_string();
+        backup_name.push(format!(".{}.{BACKUP_EXTENSION}", Local::now().format(TIMESTAMP_FORMAT)));
+        write_atomically(&file_path.with_file_name(backup_name), &contents)?;
+    }
+
+    for old_backup in list_backups(file_path)?.iter().skip(backups_to_keep) {
+        fs::remove_file(old_backup)?;
+    }
+
+    Ok(())
+}
+
+/// Copies `source` to `<state file name>.v<version>.bak` before it's upgraded from `version` of
+/// the format, returning the path of the copy.
+///
+/// These are never pruned, and an existing one is kept as it's the oldest copy in that version.
+pub fn create_migration_backup(source: &Path, file_path: &Path, version: u64) -> Result<PathBuf> {
+    let mut backup_name = file_path.file_name().unwrap_or_default().to_os_string();
+    backup_name.push(format!(".v{version}.{BACKUP_EXTENSION}"));
+    let backup = file_path.with_file_name(backup_name);
+
+    if !backup.try_exists()? {
+        write_atomically(&backup, &fs::read(source)?)?;
+    }
+    Ok(backup)
+}
+
+/// Replaces the state file with `backup`, backing up the current state file first.
+pub fn restore_backup(config: &StorageConfig, backup: &Path) -> Result<()> {
+    if let Err(error) = config.backend.open(backup).load() {
+        bail!("{} can't be restored: {error}", backup.display());
+    }
+
+    let contents = fs::read(backup)?;
+    create_backup(&config.file_path, config.backups_to_keep.max(1))?;
+    write_atomically(&config.file_path, &contents)
+}
+
+/// When the backup was taken, read from its name.
+pub fn backup_time(backup: &Path) -> Option<NaiveDateTime> {
+    let name = backup.file_stem()?.to_string_lossy().into_owned();
+    let (_, timestamp) = name.rsplit_once('.')?;
+    NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT).ok()
+}
+
+/// Renames the state file to `<state file name>.corrupt-<timestamp>`, returning the new path.
+pub fn move_aside(file_path: &Path) -> Result<PathBuf> {
+    let mut new_name = file_path.file_name().unwrap_or_default().to_os_string();
+    new_name.push(format!(".corrupt-{}", Local::now().format(TIMESTAMP_FORMAT)));
+
+    let new_path = file_path.with_file_name(new_name);
+    fs::rename(file_path, &new_path)?;
+    Ok(new_path)
+}
+
+fn parent_dir(file_path: &Path) -> Option<&Path> {
+    match file_path.parent() {
+        Some(dir) if dir.as_os_str().is_empty() => Some(Path::new(".")),
+        dir => dir,
+    }
+}
@@ -0,0 +1,159 @@
+//! Operations done on the tasks, recorded as they happen so backends can append them instead of
+//! rewriting everything, and so there's a trail of when each task changed.
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::AppStorage;
+use crate::{
+    entities::{DateKind, ParentTask, Subtree, Task, TaskId, TaskStatus},
+    history::TaskChanges,
+};
+
+/// A change to the tasks, with enough data to redo it on the tasks as they were before it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    Insert {
+        task: Task,
+        /// Position among its siblings, `None` for the end.
+        index: Option<usize>,
+    },
+    /// A subtree pasted from the register, with the ids it got once pasted.
+    InsertSubtree {
+        parent: ParentTask,
+        index: Option<usize>,
+        /// The root of the subtree first, a list as maps keyed by id can't be read back when
+        /// flattened into an entry.
+        tasks: Vec<Task>,
+    },
+    /// Removes the task with all its descendants.
+    Remove {
+        id: TaskId,
+    },
+    Retitle {
+        id: TaskId,
+        title: String,
+    },
+    SetStatus {
+        id: TaskId,
+        status: TaskStatus,
+    },
+    SetNotes {
+        id: TaskId,
+        notes: String,
+    },
+    SetDate {
+        id: TaskId,
+        kind: DateKind,
+        date: Option<NaiveDate>,
+    },
+    Move {
+        id: TaskId,
+        parent: ParentTask,
+        index: Option<usize>,
+    },
+    Swap {
+        parent: ParentTask,
+        first: TaskId,
+        second: TaskId,
+    },
+    /// Tasks put back as they were in another state, e.g. by undo or redo.
+    Restore {
+        changes: TaskChanges,
+    },
+    /// The tasks were replaced as a whole, e.g. by reloading external changes, so only a full
+    /// save can record it.
+    Replace,
+}
+
+impl Operation {
+    /// The tasks it changes, other than the parents tasks are taken from.
+    pub fn task_ids(&self) -> Vec<TaskId> {
+        let parent_id = |parent: &ParentTask| match parent {
+            ParentTask::Id(id) => Some(*id),
+            ParentTask::Root => None,
+        };
+
+        match self {
+            Operation::Insert { task, .. } => [Some(task.id), parent_id(&task.parent)].into_iter().flatten().collect(),
+            Operation::InsertSubtree { parent, tasks, .. } => {
+                tasks.iter().map(|task| task.id).chain(parent_id(parent)).collect()
+            }
+            Operation::Remove { id }
+            | Operation::Retitle { id, .. }
+            | Operation::SetStatus { id, .. }
+            | Operation::SetNotes { id, .. }
+            | Operation::SetDate { id, .. } => vec![*id],
+            Operation::Move { id, parent, .. } => [Some(*id), parent_id(parent)].into_iter().flatten().collect(),
+            Operation::Swap { parent, first, second } => [Some(*first), Some(*second), parent_id(parent)]
+                .into_iter()
+                .flatten()
+                .collect(),
+            Operation::Restore { changes } => changes.ids().collect(),
+            Operation::Replace => vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub operation: Operation,
+}
+
+impl AppStorage {
+    /// Adds an operation to the ones waiting to be handed to the writer, returns the time it was
+    /// done at, for the timestamps it sets.
+    pub(super) fn record(&mut self, operation: Operation) -> DateTime<Utc> {
+        let at = self.now();
+        self.journal.push(JournalEntry { at, operation });
+        at
+    }
+
+    /// The time to set in the timestamps of tasks, the time of the operation being redone if any.
+    pub(super) fn now(&self) -> DateTime<Utc> {
+        self.replaying_at.unwrap_or_else(Utc::now)
+    }
+
+    /// Redoes a recorded operation as it was done, `None` if it doesn't apply to the current
+    /// tasks.
+    pub(super) fn apply(&mut self, entry: &JournalEntry) -> Option<()> {
+        self.replaying_at = Some(entry.at);
+        let result = self.apply_operation(entry.operation.clone());
+        self.replaying_at = None;
+        result
+    }
+
+    fn apply_operation(&mut self, operation: Operation) -> Option<()> {
+        match operation {
+            Operation::Insert { task, index } => self.attach_task(task, index),
+            Operation::InsertSubtree { parent, index, tasks } => {
+                let root = tasks.first()?.id;
+                let tasks = tasks.into_iter().map(|task| (task.id, task)).collect();
+                (self.insert_subtree(parent, Subtree { root, tasks }, index)? == root).then_some(())
+            }
+            Operation::Remove { id } => self.remove_task(id).map(|_| ()),
+            Operation::Retitle { id, title } => self.tasks.contains_key(&id).then(|| self.update_task_title(id, title)),
+            Operation::SetStatus { id, status } => self
+                .tasks
+                .contains_key(&id)
+                .then(|| self.update_task_status(id, status)),
+            Operation::SetNotes { id, notes } => {
+                self.tasks.contains_key(&id).then(|| self.update_task_notes(id, notes))
+            }
+            Operation::SetDate { id, kind, date } => self
+                .tasks
+                .contains_key(&id)
+                .then(|| self.update_task_date(id, kind, date)),
+            Operation::Move { id, parent, index } => self.move_task(id, parent, index),
+            Operation::Swap { parent, first, second } => self.swap_sub_tasks(parent, first, second),
+            Operation::Restore { changes } => {
+                changes.apply(&mut self.tasks);
+                Some(())
+            }
+            Operation::Replace => None,
+        }
+    }
+}
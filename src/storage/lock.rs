@@ -0,0 +1,77 @@
+//! Advisory lock that keeps two instances from overwriting each other's changes.
+//!
+//! The lock is taken on a `<state file>.lock` file rather than on the state file itself, because
+//! saving replaces the state file with a new one.
+
+use std::{
+    io::{Read, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::Result;
+use fs_err as fs;
+
+/// Released when dropped, or by the OS if the process dies.
+#[derive(Debug)]
+pub struct StateLock {
+    _file: fs::File,
+}
+
+pub enum LockAttempt {
+    Acquired(StateLock),
+    /// Another process holds the lock, with its pid unless it hasn't written it yet.
+    HeldBy(Option<u32>),
+}
+
+impl LockAttempt {
+    /// Who holds the lock, for messages.
+    pub fn describe_holder(pid: Option<u32>) -> String {
+        match pid {
+            Some(pid) => format!("process {pid}"),
+            None => "another process".to_owned(),
+        }
+    }
+}
+
+/// Takes the lock unless another process holds it.
+///
+/// The OS releases the lock of a process that dies, so a held lock is never stale, even if the
+/// pid in the file can't be read yet.
+pub fn lock_state_file(file_path: &Path) -> Result<LockAttempt> {
+    match try_lock(&lock_path(file_path))? {
+        Ok(lock) => Ok(LockAttempt::Acquired(lock)),
+        Err(pid) => Ok(LockAttempt::HeldBy(pid)),
+    }
+}
+
+/// Tries to lock `lock_path`, on failure returns the pid written by the holder, if any.
+fn try_lock(lock_path: &Path) -> Result<Result<StateLock, Option<u32>>> {
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(lock_path)?;
+
+    match file.try_lock() {
+        Ok(()) => {
+            file.set_len(0)?;
+            file.rewind()?;
+            write!(file, "{}", std::process::id())?;
+            file.sync_all()?;
+            Ok(Ok(StateLock { _file: file }))
+        }
+        Err(std::fs::TryLockError::WouldBlock) => {
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)?;
+            Ok(Err(contents.trim().parse().ok()))
+        }
+        Err(std::fs::TryLockError::Error(error)) => Err(error.into()),
+    }
+}
+
+fn lock_path(file_path: &Path) -> PathBuf {
+    let mut lock_name = file_path.file_name().unwrap_or_default().to_os_string();
+    lock_name.push(".lock");
+    file_path.with_file_name(lock_name)
+}
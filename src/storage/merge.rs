@@ -0,0 +1,303 @@
+//! Three-way merge of task trees, used when the state file changes while there are unsaved changes.
+
+use indexmap::{IndexMap, IndexSet};
+use serde_json::Value;
+
+use crate::entities::{ParentTask, Task, TaskId};
+
+pub struct MergeResult {
+    pub tasks: IndexMap<TaskId, Task>,
+    /// Tasks changed differently on both sides, these kept our version.
+    pub conflicts: Vec<TaskId>,
+}
+
+/// Merges the changes from `base` to `ours` and from `base` to `theirs`.
+///
+/// Tasks changed on a single side take that side's version, tasks changed on both are merged field
+/// by field, and children lists are merged by applying their additions and removals on top of ours.
+///
+/// The result may be inconsistent, e.g. a task moved to different parents on each side, so it
+/// needs repairing afterwards.
+pub fn merge_tasks(
+    base: &IndexMap<TaskId, Task>,
+    ours: &IndexMap<TaskId, Task>,
+    theirs: &IndexMap<TaskId, Task>,
+) -> MergeResult {
+    // Root tasks are ordered by their position in the map, keep the order of whoever changed it
+    let we_reordered_roots = !root_ids(ours).eq(root_ids(base));
+    let (first, second) = match we_reordered_roots {
+        true => (ours, theirs),
+        false => (theirs, ours),
+    };
+    let ids = first
+        .keys()
+        .chain(second.keys())
+        .chain(base.keys())
+        .collect::<IndexSet<_>>();
+
+    let mut tasks = IndexMap::with_capacity(ids.len());
+    let mut conflicts = vec![];
+
+    for id in ids {
+        let (task, is_conflict) = merge_task(base.get(id), ours.get(id), theirs.get(id));
+        if let Some(task) = task {
+            tasks.insert(*id, task);
+        }
+        if is_conflict {
+            conflicts.push(*id);
+        }
+    }
+
+    MergeResult { tasks, conflicts }
+}
+
+fn merge_task(base: Option<&Task>, ours: Option<&Task>, theirs: Option<&Task>) -> (Option<Task>, bool) {
+    if ours == theirs || theirs == base {
+        return (ours.cloned(), false);
+    }
+    if ours == base {
+        return (theirs.cloned(), false);
+    }
+
+    match (base, ours, theirs) {
+        (Some(base), Some(ours), Some(theirs)) => {
+            let (mut task, is_conflict) = merge_fields(base, ours, theirs);
+            task.children = merge_children(&base.children, &ours.children, &theirs.children);
+            (Some(task), is_conflict)
+        }
+        // Deleted on one side but changed on the other, keep the changes
+        (_, Some(task), None) | (_, None, Some(task)) => (Some(task.clone()), true),
+        // Created on both sides with the same id
+        (None, Some(ours), Some(_)) => (Some(ours.clone()), true),
+        (_, None, None) => (None, false),
+    }
+}
+
+/// Takes each field from the side that changed it, fields changed on both sides keep our value.
+///
+/// `updated_at` changes on both sides whenever both edit the task, so it takes the latest instead.
+fn merge_fields(base: &Task, ours: &Task, theirs: &Task) -> (Task, bool) {
+    let updated_at = ours.updated_at.max(theirs.updated_at);
+    let [Value::Object(base), Value::Object(mut merged), Value::Object(theirs)] =
+        [base, ours, theirs].map(|task| serde_json::to_value(task).expect("tasks serialize to JSON"))
+    else {
+        unreachable!("tasks serialize to JSON objects");
+    };
+
+    let mut is_conflict = false;
+    for (field, ours_value) in merged.iter_mut() {
+        let (base_value, theirs_value) = (base.get(field), theirs.get(field));
+
+        if base_value == Some(ours_value) {
+            if let Some(theirs_value) = theirs_value {
+                *ours_value = theirs_value.clone();
+            }
+        } else if theirs_value != base_value
+            && theirs_value != Some(ours_value)
+            && !matches!(field.as_str(), "children" | "updated_at")
+        {
+            is_conflict = true;
+        }
+    }
+
+    let mut task: Task = serde_json::from_value(Value::Object(merged)).expect("fields come from valid tasks");
+    task.updated_at = updated_at;
+    (task, is_conflict)
+}
+
+fn merge_children(base: &[TaskId], ours: &[TaskId], theirs: &[TaskId]) -> Vec<TaskId> {
+    if ours == base {
+        return theirs.to_vec();
+    }
+    if theirs == base {
+        return ours.to_vec();
+    }
+
+    let mut merged = ours
+        .iter()
+        .copied()
+        .filter(|id| theirs.contains(id) || !base.contains(id))
+        .collect::<Vec<_>>();
+
+    for id in theirs {
+        if !base.contains(id) && !merged.contains(id) {
+            merged.push(*id);
+        }
+    }
+    merged
+}
+
+fn root_ids(tasks: &IndexMap<TaskId, Task>) -> impl Iterator<Item = TaskId> {
+    tasks
+        .values()
+        .filter(|task| task.parent == ParentTask::Root)
+        .map(|task| task.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeDelta, Utc};
+
+    use super::*;
+    use crate::entities::TaskStatus;
+
+    /// Roots "A", "B" and "C", with "A" holding "a1" and "a2".
+    fn base_tree() -> IndexMap<TaskId, Task> {
+        let mut tasks = IndexMap::new();
+        for title in ["A", "B", "C"] {
+            add_task(&mut tasks, ParentTask::Root, title);
+        }
+        let a = find(&tasks, "A");
+        for title in ["a1", "a2"] {
+            add_task(&mut tasks, ParentTask::Id(a), title);
+        }
+        tasks
+    }
+
+    fn add_task(tasks: &mut IndexMap<TaskId, Task>, parent: ParentTask, title: &str) -> TaskId {
+        let task = Task {
+            id: TaskId::new(),
+            parent,
+            title: title.to_owned(),
+            ..Task::default()
+        };
+        if let ParentTask::Id(parent_id) = parent {
+            tasks[&parent_id].children.push(task.id);
+        }
+        let id = task.id;
+        tasks.insert(id, task);
+        id
+    }
+
+    fn remove_task(tasks: &mut IndexMap<TaskId, Task>, id: TaskId) {
+        let task = tasks.shift_remove(&id).unwrap();
+        if let ParentTask::Id(parent_id) = task.parent {
+            tasks[&parent_id].children.retain(|child| *child != id);
+        }
+    }
+
+    fn find(tasks: &IndexMap<TaskId, Task>, title: &str) -> TaskId {
+        tasks.values().find(|task| task.title == title).unwrap().id
+    }
+
+    fn titles(tasks: &IndexMap<TaskId, Task>, ids: impl IntoIterator<Item = TaskId>) -> Vec<String> {
+        ids.into_iter().map(|id| tasks[&id].title.clone()).collect()
+    }
+
+    fn reorder_roots(tasks: &mut IndexMap<TaskId, Task>, order: [&str; 3]) {
+        for (index, title) in order.into_iter().enumerate() {
+            let from = tasks.get_index_of(&find(tasks, title)).unwrap();
+            tasks.move_index(from, index);
+        }
+    }
+
+    #[test]
+    fn merges_edits_to_different_fields() {
+        let base = base_tree();
+        let a1 = find(&base, "a1");
+        let (mut ours, mut theirs) = (base.clone(), base.clone());
+        let earlier = Utc::now() - TimeDelta::hours(1);
+        let later = Utc::now();
+
+        ours[&a1].title = "a1 renamed".to_owned();
+        ours[&a1].updated_at = Some(later);
+        theirs[&a1].status = TaskStatus::Done;
+        theirs[&a1].notes = "Some notes".to_owned();
+        theirs[&a1].updated_at = Some(earlier);
+
+        let merged = merge_tasks(&base, &ours, &theirs);
+        assert!(merged.conflicts.is_empty());
+        let task = &merged.tasks[&a1];
+        assert_eq!(task.title, "a1 renamed");
+        assert_eq!(task.status, TaskStatus::Done);
+        assert_eq!(task.notes, "Some notes");
+        assert_eq!(task.updated_at, Some(later));
+    }
+
+    #[test]
+    fn keeps_our_value_for_fields_changed_on_both_sides() {
+        let base = base_tree();
+        let (a1, a2) = (find(&base, "a1"), find(&base, "a2"));
+        let (mut ours, mut theirs) = (base.clone(), base.clone());
+
+        ours[&a1].title = "Ours".to_owned();
+        theirs[&a1].title = "Theirs".to_owned();
+        theirs[&a1].status = TaskStatus::Done;
+        // The same change on both sides isn't a conflict
+        ours[&a2].title = "Both".to_owned();
+        theirs[&a2].title = "Both".to_owned();
+
+        let merged = merge_tasks(&base, &ours, &theirs);
+        assert_eq!(merged.conflicts, [a1]);
+        assert_eq!(merged.tasks[&a1].title, "Ours");
+        assert_eq!(merged.tasks[&a1].status, TaskStatus::Done);
+        assert_eq!(merged.tasks[&a2].title, "Both");
+    }
+
+    #[test]
+    fn keeps_tasks_edited_on_one_side_and_deleted_on_the_other() {
+        let base = base_tree();
+        let a2 = find(&base, "a2");
+
+        let mut ours = base.clone();
+        ours[&a2].title = "Edited here".to_owned();
+        let mut theirs = base.clone();
+        remove_task(&mut theirs, a2);
+        let merged = merge_tasks(&base, &ours, &theirs);
+        assert_eq!(merged.conflicts, [a2]);
+        assert_eq!(merged.tasks[&a2].title, "Edited here");
+
+        let mut ours = base.clone();
+        remove_task(&mut ours, a2);
+        let mut theirs = base.clone();
+        theirs[&a2].title = "Edited outside".to_owned();
+        let merged = merge_tasks(&base, &ours, &theirs);
+        assert_eq!(merged.conflicts, [a2]);
+        assert_eq!(merged.tasks[&a2].title, "Edited outside");
+
+        // Deleted on both sides stays deleted
+        let merged = merge_tasks(&base, &ours, &ours);
+        assert!(merged.conflicts.is_empty());
+        assert!(!merged.tasks.contains_key(&a2));
+    }
+
+    #[test]
+    fn merges_children_added_and_removed_on_both_sides() {
+        let base = base_tree();
+        let (a, a1) = (find(&base, "A"), find(&base, "a1"));
+        let (mut ours, mut theirs) = (base.clone(), base.clone());
+
+        add_task(&mut ours, ParentTask::Id(a), "a3");
+        remove_task(&mut theirs, a1);
+        add_task(&mut theirs, ParentTask::Id(a), "a4");
+
+        let merged = merge_tasks(&base, &ours, &theirs);
+        assert!(merged.conflicts.is_empty());
+        assert!(!merged.tasks.contains_key(&a1));
+        let children = merged.tasks[&a].children.clone();
+        assert_eq!(titles(&merged.tasks, children), ["a2", "a3", "a4"]);
+    }
+
+    #[test]
+    fn keeps_the_root_order_of_the_side_that_changed_it() {
+        let base = base_tree();
+        let b = find(&base, "B");
+        let merged_roots = |ours: &IndexMap<TaskId, Task>, theirs: &IndexMap<TaskId, Task>| {
+            let merged = merge_tasks(&base, ours, theirs);
+            assert!(merged.conflicts.is_empty());
+            titles(&merged.tasks, root_ids(&merged.tasks))
+        };
+
+        let mut reordered = base.clone();
+        reorder_roots(&mut reordered, ["C", "A", "B"]);
+        let mut edited = base.clone();
+        edited[&b].title = "B renamed".to_owned();
+        assert_eq!(merged_roots(&reordered, &edited), ["C", "A", "B renamed"]);
+        assert_eq!(merged_roots(&edited, &reordered), ["C", "A", "B renamed"]);
+
+        // Both reordered, ours wins
+        let mut reordered_outside = base.clone();
+        reorder_roots(&mut reordered_outside, ["B", "C", "A"]);
+        assert_eq!(merged_roots(&reordered, &reordered_outside), ["C", "A", "B"]);
+    }
+}
@@ -1,76 +1,357 @@
-use std::{path::PathBuf, sync::LazyLock};
-
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
 use color_eyre::Result;
 use fs_err as fs;
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 
-use crate::entities::{ParentTask, Task, TaskData};
-
+use crate::{
+    entities::{DateKind, ParentTask, Subtree, Task, TaskData, TaskId, TaskStatus},
+    history::TaskChanges,
+};
+
+mod atomic;
+mod backend;
+mod backup;
+mod journal;
+mod lock;
+mod merge;
+mod path;
+mod schema;
+mod sync;
 mod view;
-use view::ViewStorage;
-
-static FILE_PATH: LazyLock<PathBuf> = std::sync::LazyLock::new(|| {
-    let mut path = PathBuf::from(std::env::var("HOME").expect("There is no $HOME"));
-    path.push("nest_state.json");
-    path
-});
-
-#[derive(Default, Debug, Serialize, Deserialize)]
+mod watcher;
+mod writer;
+pub use atomic::write_atomically;
+pub use backend::BackendKind;
+pub use backup::{backup_time, list_backups, move_aside, restore_backup};
+use journal::{JournalEntry, Operation};
+use lock::StateLock;
+pub use lock::{LockAttempt, lock_state_file};
+pub use path::{file_name, resolve_file_path};
+pub use schema::CURRENT_VERSION;
+use view::{Jump, ViewStorage};
+use watcher::{FileStamp, FileWatcher};
+use writer::{StorageWriter, TasksUpdate};
+
+#[derive(Default, Debug)]
 pub struct AppStorage {
     pub view: ViewStorage,
-    pub tasks: IndexMap<u64, Task>,
+    pub tasks: IndexMap<TaskId, Task>,
+    /// When the tasks were last changed, if they weren't saved since.
+    tasks_changed_at: Option<Instant>,
+    /// Operations done since the last save.
+    journal: Vec<JournalEntry>,
+    /// `None` when there's nothing to save to, like in a storage that wasn't loaded from a file.
+    writer: Option<StorageWriter>,
+    /// Held while the writer is alive, declared after it so it's released after the last write.
+    lock: Option<StateLock>,
+    /// Why changes aren't being saved, if they aren't.
+    read_only_reason: Option<String>,
+    /// `None` when not loaded from a file.
+    file_path: Option<PathBuf>,
+    backend: BackendKind,
+    watcher: Option<FileWatcher>,
+    /// The version of the state file last written or read by this instance, shared with the writer.
+    disk_stamp: Arc<Mutex<Option<FileStamp>>>,
+    /// The tasks as they were last saved or loaded, the base when merging external changes.
+    saved_tasks: IndexMap<TaskId, Task>,
+    /// Whether `saved_tasks` were reloaded from the file instead of sent to the writer, which then
+    /// needs all the tasks rather than the changes.
+    saved_tasks_reloaded: bool,
+    /// What happened while loading or reloading the file, for showing to the user.
+    sync_message: Option<String>,
+    /// When the operation being redone from a journal was done, see `now`.
+    replaying_at: Option<DateTime<Utc>>,
+}
+
+/// What's kept in the state file.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct StateFile {
+    /// Version of the layout, see `schema`.
+    pub version: u64,
+    pub view: ViewStorage,
+    pub tasks: Arc<IndexMap<TaskId, Task>>,
+}
+
+/// Loading failed because of what's in the state file, e.g. it was cut short or overwritten, so
+/// it's worth offering to recover from a backup rather than giving up.
+#[derive(Debug)]
+pub struct CorruptStateFile(Box<dyn Error + Send + Sync>);
+
+impl CorruptStateFile {
+    pub fn new(error: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        CorruptStateFile(error.into())
+    }
+}
+
+impl fmt::Display for CorruptStateFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Error for CorruptStateFile {}
+
+/// Minimum time between two backups taken while nest is running.
+const BACKUP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// How long changes to the tasks wait before being saved, so bursts of edits are saved once.
+const TASKS_SAVE_DELAY: Duration = Duration::from_millis(300);
+
+/// Like `TASKS_SAVE_DELAY`, but for the view state, that changes on every move and matters less.
+const VIEW_SAVE_DELAY: Duration = Duration::from_secs(3);
+
+/// How often to check the file watcher for changes made outside of nest.
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+
+pub const DEFAULT_BACKUPS_TO_KEEP: usize = 5;
+
+#[derive(Debug, Clone, Default)]
+pub struct StorageConfig {
+    pub file_path: PathBuf,
+    pub backend: BackendKind,
+    /// How many rolling backups to keep next to the state file.
+    pub backups_to_keep: usize,
+    /// Open without locking nor writing the state file.
+    pub read_only: bool,
 }
 
 impl AppStorage {
-    pub fn get_task(&self, task_id: u64) -> Option<&Task> {
+    pub fn get_task(&self, task_id: TaskId) -> Option<&Task> {
         self.tasks.get(&task_id)
     }
 
-    pub fn insert_task(&mut self, parent: ParentTask, task_data: TaskData) {
+    /// Adds a task as the last child of `parent`, `None` if the parent is gone.
+    pub fn insert_task(&mut self, parent: ParentTask, task_data: TaskData) -> Option<TaskId> {
         let mut task = self.create_task(task_data);
         task.parent = parent;
+        let task_id = task.id;
 
         if let ParentTask::Id(parent_id) = parent {
-            self.tasks.entry(parent_id).or_default().children.push(task.id);
+            self.tasks.get_mut(&parent_id)?.children.push(task.id);
         }
 
+        self.record(Operation::Insert {
+            task: task.clone(),
+            index: None,
+        });
         self.tasks.insert(task.id, task);
+        self.mark_tasks_changed();
+        Some(task_id)
     }
 
-    pub fn insert_task_at(&mut self, parent: ParentTask, task_data: TaskData, index: usize) -> Option<()> {
+    pub fn insert_task_at(&mut self, parent: ParentTask, task_data: TaskData, index: usize) -> Option<TaskId> {
         let mut task = self.create_task(task_data);
         task.parent = parent;
+        let task_id = task.id;
+
+        self.attach_task(task.clone(), Some(index))?;
+        self.record(Operation::Insert {
+            task,
+            index: Some(index),
+        });
+        Some(task_id)
+    }
 
-        match parent {
-            ParentTask::Id(parent_id) => {
-                self.tasks.entry(parent_id).or_default().children.insert(index, task.id);
+    /// Inserts a detached subtree under `parent`, at `index` or at the end.
+    ///
+    /// The original ids are kept when none of them is in use, so a cut and paste keeps
+    /// references to the moved tasks valid, otherwise the whole subtree gets fresh ids.
+    ///
+    /// Returns the id of the inserted subtree root.
+    pub fn insert_subtree(&mut self, parent: ParentTask, subtree: Subtree, index: Option<usize>) -> Option<TaskId> {
+        if let ParentTask::Id(parent_id) = parent {
+            self.tasks.get(&parent_id)?;
+        }
+
+        let Subtree { root, mut tasks } = subtree;
+        // Children that aren't in the subtree would be left pointing at nothing
+        let ids: HashSet<TaskId> = tasks.keys().copied().collect();
+        tasks
+            .values_mut()
+            .for_each(|task| task.children.retain(|id| ids.contains(id)));
+
+        let root = if tasks.keys().any(|id| self.tasks.contains_key(id)) {
+            let new_ids = tasks.keys().map(|id| (*id, TaskId::new())).collect::<HashMap<_, _>>();
+            let copied_at = self.now();
+
+            tasks = tasks
+                .into_values()
+                .map(|mut task| {
+                    task.id = new_ids[&task.id];
+                    task.created_at = Some(copied_at);
+                    task.updated_at = Some(copied_at);
+                    if let ParentTask::Id(parent_id) = task.parent {
+                        task.parent = new_ids.get(&parent_id).map_or(task.parent, |id| ParentTask::Id(*id));
+                    }
+                    task.children.iter_mut().for_each(|id| *id = new_ids[id]);
+                    (task.id, task)
+                })
+                .collect();
+
+            new_ids[&root]
+        } else {
+            root
+        };
+
+        let mut root_task = tasks.shift_remove(&root)?;
+        root_task.parent = parent;
+        self.attach_task(root_task.clone(), index)?;
+
+        self.record(Operation::InsertSubtree {
+            parent,
+            index,
+            tasks: std::iter::once(root_task).chain(tasks.values().cloned()).collect(),
+        });
+
+        self.tasks.extend(tasks);
+        self.mark_tasks_changed();
+
+        Some(root)
+    }
+
+    /// Copies the task and all its descendants, without removing them.
+    pub fn copy_subtree(&self, task_id: TaskId) -> Option<Subtree> {
+        let tasks = self
+            .find_subtree_ids(task_id)
+            .into_iter()
+            .filter_map(|id| Some((id, self.tasks.get(&id)?.clone())))
+            .collect::<IndexMap<_, _>>();
+
+        tasks.contains_key(&task_id).then_some(Subtree { root: task_id, tasks })
+    }
+
+    /// Moves a task (and its descendants) under `new_parent`, at `index` or at the end.
+    ///
+    /// Refuses to move a task inside of its own subtree.
+    pub fn move_task(&mut self, task_id: TaskId, new_parent: ParentTask, index: Option<usize>) -> Option<()> {
+        if let ParentTask::Id(new_parent_id) = new_parent
+            && (!self.tasks.contains_key(&new_parent_id) || self.find_subtree_ids(task_id).contains(&new_parent_id))
+        {
+            return None;
+        }
+
+        let current_parent = self.tasks.get(&task_id)?.parent;
+        let mut siblings_count = self.find_children(new_parent).len();
+        if current_parent == new_parent {
+            siblings_count -= 1;
+        }
+        if index.is_some_and(|index| index > siblings_count) {
+            return None;
+        }
+
+        let mut task = self.detach_task(task_id)?;
+        task.parent = new_parent;
+        self.attach_task(task, index)?;
+        let moved_at = self.record(Operation::Move {
+            id: task_id,
+            parent: new_parent,
+            index,
+        });
+        self.tasks[&task_id].updated_at = Some(moved_at);
+        Some(())
+    }
 
+    /// Position of the task among its siblings.
+    pub fn find_position_in_parent(&self, task_id: TaskId) -> Option<usize> {
+        let parent = self.tasks.get(&task_id)?.parent;
+        self.find_children(parent).iter().position(|task| task.id == task_id)
+    }
+
+    /// Makes the task the last child of its previous sibling.
+    pub fn indent_task(&mut self, task_id: TaskId) -> Option<()> {
+        let parent = self.tasks.get(&task_id)?.parent;
+        let position = self.find_position_in_parent(task_id)?;
+        let previous_sibling_id = self.find_children(parent).get(position.checked_sub(1)?)?.id;
+
+        self.move_task(task_id, ParentTask::Id(previous_sibling_id), None)
+    }
+
+    /// Makes the task a sibling of its parent, placed right after it.
+    pub fn outdent_task(&mut self, task_id: TaskId) -> Option<()> {
+        let ParentTask::Id(parent_id) = self.tasks.get(&task_id)?.parent else {
+            return None;
+        };
+        let grandparent = self.tasks.get(&parent_id)?.parent;
+        let parent_position = self.find_position_in_parent(parent_id)?;
+
+        self.move_task(task_id, grandparent, Some(parent_position + 1))
+    }
+
+    /// Places a task in the map and in its parent's children, at `index` or at the end.
+    ///
+    /// Root tasks have no parent to hold their order, so their position in the map is used instead.
+    fn attach_task(&mut self, task: Task, index: Option<usize>) -> Option<()> {
+        match task.parent {
+            ParentTask::Id(parent_id) => {
+                let children = &mut self.tasks.get_mut(&parent_id)?.children;
+                let index = index.unwrap_or(children.len());
+                if index > children.len() {
+                    return None;
+                }
+                children.insert(index, task.id);
                 self.tasks.insert(task.id, task);
             }
             ParentTask::Root => {
+                let root_tasks_count = self.find_root_tasks().len();
+                let index = index.unwrap_or(root_tasks_count);
+                if index > root_tasks_count {
+                    return None;
+                }
+
                 let target_index_map_entry = self
                     .tasks
                     .iter()
                     .filter(|(_, task)| task.parent == ParentTask::Root)
                     .nth(index)
-                    .and_then(|(id, _)| self.tasks.get_index_of(id))?;
+                    .and_then(|(id, _)| self.tasks.get_index_of(id));
 
-                self.tasks.shift_insert(target_index_map_entry, task.id, task);
+                match target_index_map_entry {
+                    Some(map_index) => _ = self.tasks.shift_insert(map_index, task.id, task),
+                    None => _ = self.tasks.insert(task.id, task),
+                }
             }
         }
 
+        self.mark_tasks_changed();
         Some(())
     }
 
+    /// Removes a task from the map and from its parent's children, leaving its descendants in place.
+    fn detach_task(&mut self, task_id: TaskId) -> Option<Task> {
+        let parent = self.tasks.get(&task_id)?.parent;
+
+        if let ParentTask::Id(parent_id) = parent
+            && let Some(parent_task) = self.tasks.get_mut(&parent_id)
+        {
+            parent_task.children.retain(|id| *id != task_id);
+        }
+
+        self.mark_tasks_changed();
+        self.tasks.shift_remove(&task_id)
+    }
+
     pub fn find_parents_stack(&self) -> Vec<&Task> {
-        let mut parents = Vec::new();
+        match self.view.get_opened_task() {
+            ParentTask::Id(id) => self.find_task_stack(id),
+            ParentTask::Root => vec![],
+        }
+    }
 
-        let mut current_id = match self.view.get_opened_task() {
-            ParentTask::Id(id) => id,
-            ParentTask::Root => return vec![],
-        };
+    /// The task followed by its parents, up to a root task.
+    pub fn find_task_stack(&self, task_id: TaskId) -> Vec<&Task> {
+        let mut parents = Vec::new();
 
+        let mut current_id = task_id;
         while let Some(task) = self.tasks.get(&current_id) {
             parents.push(task);
             match task.parent {
@@ -82,7 +363,22 @@ impl AppStorage {
     }
 
     pub fn find_opened_sub_tasks(&self) -> Vec<&Task> {
-        match self.view.get_opened_task() {
+        self.find_children(self.view.get_opened_task())
+    }
+
+    /// Every task, each one followed by its subtree, in the order they're shown.
+    pub fn find_all_tasks(&self) -> Vec<&Task> {
+        let mut all_tasks = Vec::new();
+        let mut to_visit: Vec<_> = self.find_root_tasks().into_iter().rev().collect();
+        while let Some(task) = to_visit.pop() {
+            all_tasks.push(task);
+            to_visit.extend(self.find_sub_tasks(task.id).into_iter().rev());
+        }
+        all_tasks
+    }
+
+    pub fn find_children(&self, parent: ParentTask) -> Vec<&Task> {
+        match parent {
             ParentTask::Root => self.find_root_tasks(),
             ParentTask::Id(parent_id) => self.find_sub_tasks(parent_id),
         }
@@ -95,7 +391,7 @@ impl AppStorage {
             .collect::<Vec<_>>()
     }
 
-    pub fn find_sub_tasks(&self, parent_id: u64) -> Vec<&Task> {
+    pub fn find_sub_tasks(&self, parent_id: TaskId) -> Vec<&Task> {
         match self.tasks.get(&parent_id) {
             None => vec![],
             Some(parent_task) => parent_task
@@ -106,32 +402,161 @@ impl AppStorage {
         }
     }
 
-    pub fn remove_task(&mut self, task_id: u64) -> Option<Task> {
-        let parent = self.tasks.get(&task_id)?.parent;
+    /// Ids of the task and all its descendants, in pre-order.
+    pub fn find_subtree_ids(&self, task_id: TaskId) -> Vec<TaskId> {
+        let mut ids = vec![];
+        let mut visited = HashSet::new();
+        let mut stack = vec![task_id];
+
+        while let Some(id) = stack.pop() {
+            let Some(task) = self.tasks.get(&id) else {
+                continue;
+            };
+            if visited.insert(id) {
+                ids.push(id);
+                stack.extend(task.children.iter().rev());
+            }
+        }
+        ids
+    }
 
-        if let ParentTask::Id(parent_id) = parent {
-            self.tasks
-                .entry(parent_id)
-                .or_default()
-                .children
-                .retain(|id| *id != task_id);
+    /// Removes the task and all its descendants, returning them detached from the tree.
+    pub fn remove_task(&mut self, task_id: TaskId) -> Option<Subtree> {
+        let subtree_ids = self.find_subtree_ids(task_id).into_iter().collect::<HashSet<_>>();
+        let root_task = self.detach_task(task_id)?;
+
+        let (descendants, remaining): (IndexMap<_, _>, _) = std::mem::take(&mut self.tasks)
+            .into_iter()
+            .partition(|(id, _)| subtree_ids.contains(id));
+        self.tasks = remaining;
+
+        let mut tasks = IndexMap::from([(task_id, root_task)]);
+        tasks.extend(descendants);
+
+        self.record(Operation::Remove { id: task_id });
+        Some(Subtree { root: task_id, tasks })
+    }
+
+    /// Moves tasks whose parent is missing (or doesn't list them as children) under a new root
+    /// task, so they show up in the UI again, returns how many were found.
+    ///
+    /// Older versions didn't delete children together with their parent, leaving them stranded.
+    pub fn adopt_orphans(&mut self) -> usize {
+        // Drop children that don't exist or that point to another parent
+        let parents = self
+            .tasks
+            .values()
+            .map(|task| (task.id, task.parent))
+            .collect::<HashMap<_, _>>();
+        for task in self.tasks.values_mut() {
+            task.children
+                .retain(|id| parents.get(id) == Some(&ParentTask::Id(task.id)));
         }
 
-        // TODO: Remove all its children
+        let orphans = self
+            .tasks
+            .values()
+            .filter(|task| match task.parent {
+                ParentTask::Root => false,
+                ParentTask::Id(parent_id) => self
+                    .tasks
+                    .get(&parent_id)
+                    .is_none_or(|parent| !parent.children.contains(&task.id)),
+            })
+            .map(|task| task.id)
+            .collect::<Vec<_>>();
+
+        if orphans.is_empty() {
+            return 0;
+        }
 
-        self.tasks.shift_remove(&task_id)
+        let task_data = TaskData {
+            title: format!("Recovered orphans ({})", orphans.len()),
+            children: orphans.clone(),
+            status: TaskStatus::Todo,
+        };
+        let adopter_id = self
+            .insert_task(ParentTask::Root, task_data)
+            .expect("The root is always there");
+
+        for id in &orphans {
+            self.tasks[id].parent = ParentTask::Id(adopter_id);
+        }
+        self.record(Operation::Replace);
+        self.mark_tasks_changed();
+
+        orphans.len()
+    }
+
+    /// Adds how many orphans were recovered to the message shown, if there were any.
+    pub(super) fn report_orphans(&mut self, orphans: usize) {
+        let orphans = match orphans {
+            0 => return,
+            1 => "1 orphaned task".to_owned(),
+            orphans => format!("{orphans} orphaned tasks"),
+        };
+        self.sync_message = Some(match self.sync_message.take() {
+            Some(message) => format!("{message}, recovered {orphans}"),
+            None => format!("Recovered {orphans}"),
+        });
     }
 
-    pub fn update_task_title(&mut self, task_id: u64, new_title: String) {
-        self.tasks.entry(task_id).and_modify(|task| task.title = new_title);
+    pub fn update_task_title(&mut self, task_id: TaskId, new_title: String) {
+        let updated_at = self.record(Operation::Retitle {
+            id: task_id,
+            title: new_title.clone(),
+        });
+        self.tasks.entry(task_id).and_modify(|task| {
+            task.title = new_title;
+            task.updated_at = Some(updated_at);
+        });
+        self.mark_tasks_changed();
     }
 
-    pub fn update_task_state(&mut self, task_id: u64, done: bool) {
-        self.tasks.entry(task_id).and_modify(|task| task.done = done);
+    pub fn update_task_notes(&mut self, task_id: TaskId, notes: String) {
+        let updated_at = self.record(Operation::SetNotes {
+            id: task_id,
+            notes: notes.clone(),
+        });
+        self.tasks.entry(task_id).and_modify(|task| {
+            task.notes = notes;
+            task.updated_at = Some(updated_at);
+        });
+        self.mark_tasks_changed();
     }
 
-    pub fn swap_current_sub_tasks(&mut self, from: u64, to: u64) -> Option<()> {
-        let parent = self.view.get_opened_task();
+    pub fn update_task_date(&mut self, task_id: TaskId, kind: DateKind, date: Option<NaiveDate>) {
+        let updated_at = self.record(Operation::SetDate {
+            id: task_id,
+            kind,
+            date,
+        });
+        self.tasks.entry(task_id).and_modify(|task| {
+            *task.date_mut(kind) = date;
+            task.updated_at = Some(updated_at);
+        });
+        self.mark_tasks_changed();
+    }
+
+    pub fn update_task_status(&mut self, task_id: TaskId, status: TaskStatus) {
+        let updated_at = self.record(Operation::SetStatus { id: task_id, status });
+        self.tasks.entry(task_id).and_modify(|task| {
+            // Going from done to cancelled or marking a done task done again keeps when it was
+            // first closed
+            if task.status.is_closed() != status.is_closed() {
+                task.completed_at = status.is_closed().then_some(updated_at);
+            }
+            task.status = status;
+            task.updated_at = Some(updated_at);
+        });
+        self.mark_tasks_changed();
+    }
+
+    pub fn swap_current_sub_tasks(&mut self, from: TaskId, to: TaskId) -> Option<()> {
+        self.swap_sub_tasks(self.view.get_opened_task(), from, to)
+    }
+
+    fn swap_sub_tasks(&mut self, parent: ParentTask, from: TaskId, to: TaskId) -> Option<()> {
         match parent {
             ParentTask::Id(parent_id) => {
                 let parent_task = self.tasks.get_mut(&parent_id)?;
@@ -145,6 +570,15 @@ impl AppStorage {
                 self.tasks.swap_indices(from_index, to_index);
             }
         }
+        let moved_at = self.record(Operation::Swap {
+            parent,
+            first: from,
+            second: to,
+        });
+        for id in [from, to] {
+            self.tasks[&id].updated_at = Some(moved_at);
+        }
+        self.mark_tasks_changed();
         Some(())
     }
 
@@ -153,34 +587,290 @@ impl AppStorage {
     }
 
     pub fn set_opened_task(&mut self, opened_task: ParentTask) {
+        self.record_jump();
         self.view.set_opened_task(opened_task);
+        self.record_jump();
     }
 
     pub fn get_selected_position(&self) -> Option<usize> {
         self.view.get_selected_position()
     }
 
+    /// Opens the parent of the task and selects it.
+    pub fn select_task(&mut self, task_id: TaskId) -> Option<()> {
+        let parent = self.tasks.get(&task_id)?.parent;
+        let position = self.find_position_in_parent(task_id)?;
+
+        self.record_jump();
+        self.view.set_opened_task(parent);
+        self.view.set_selected_position(position);
+        self.record_jump();
+        Some(())
+    }
+
+    pub fn record_jump(&mut self) {
+        let selected_task = self
+            .get_selected_position()
+            .and_then(|position| self.find_opened_sub_tasks().get(position).map(|task| task.id));
+
+        self.view.record_jump(Jump {
+            opened_task: self.get_opened_task(),
+            selected_task,
+        });
+    }
+
+    /// Goes back to the previous location in the jump list, skipping locations that were deleted.
+    pub fn jump_back(&mut self) -> Option<()> {
+        self.record_jump();
+        let candidates = (0..self.view.jump_position).rev().collect::<Vec<_>>();
+        self.jump_to_first_available(candidates)
+    }
+
+    /// Goes forward to the next location in the jump list, skipping locations that were deleted.
+    pub fn jump_forward(&mut self) -> Option<()> {
+        self.record_jump();
+        let candidates = (self.view.jump_position + 1..self.view.jumps.len()).collect::<Vec<_>>();
+        self.jump_to_first_available(candidates)
+    }
+
+    fn jump_to_first_available(&mut self, candidates: Vec<usize>) -> Option<()> {
+        for jump_position in candidates {
+            let Jump {
+                opened_task,
+                selected_task,
+            } = self.view.jumps[jump_position];
+
+            if let ParentTask::Id(opened_task_id) = opened_task
+                && !self.tasks.contains_key(&opened_task_id)
+            {
+                continue;
+            }
+
+            self.view.set_jump_position(jump_position);
+            self.view.set_opened_task(opened_task);
+
+            let selected_position = selected_task.and_then(|selected_id| {
+                self.find_opened_sub_tasks()
+                    .iter()
+                    .position(|task| task.id == selected_id)
+            });
+            if let Some(position) = selected_position {
+                self.view.set_selected_position(position);
+            }
+
+            return Some(());
+        }
+        None
+    }
+
     pub fn set_selected_position(&mut self, index: usize) {
         self.view.set_selected_position(index);
     }
 
     fn create_task(&self, task_data: TaskData) -> Task {
         let mut task = Task::default().with_data(task_data);
+        task.id = TaskId::new();
+        task.created_at = Some(self.now());
+        task.updated_at = task.created_at;
+        task
+    }
 
-        let biggest_id = self.tasks.keys().max().unwrap_or(&0);
-        task.id = biggest_id + 1;
+    /// Puts tasks back as they were in another state, e.g. when undoing.
+    pub fn restore_tasks(&mut self, changes: TaskChanges) {
+        changes.apply(&mut self.tasks);
+        self.record(Operation::Restore { changes });
+        self.mark_tasks_changed();
+    }
 
-        task
+    fn mark_tasks_changed(&mut self) {
+        self.tasks_changed_at = Some(Instant::now());
+    }
+
+    /// How long until unsaved changes are due to be saved, `None` if there are none.
+    pub fn time_until_save(&self) -> Option<Duration> {
+        let tasks_save_at = self.tasks_changed_at.map(|at| at + TASKS_SAVE_DELAY);
+        let view_save_at = self.view.changed_at().map(|at| at + VIEW_SAVE_DELAY);
+
+        let save_at = tasks_save_at.into_iter().chain(view_save_at).min()?;
+        Some(save_at.saturating_duration_since(Instant::now()))
+    }
+
+    /// How long the UI can wait for input before calling `update`, `None` if it can wait forever.
+    pub fn time_until_update(&self) -> Option<Duration> {
+        let watch_interval = self.watcher.as_ref().map(|_| WATCH_INTERVAL);
+        self.time_until_save().into_iter().chain(watch_interval).min()
+    }
+
+    /// Reloads external changes to the state file and saves if changes were left unsaved for long enough.
+    pub fn update(&mut self) -> Result<()> {
+        if self.watcher.as_ref().is_some_and(FileWatcher::has_changed) {
+            self.reload_if_changed_externally()?;
+        }
+
+        match self.time_until_save() {
+            Some(Duration::ZERO) => self.save(),
+            _ => Ok(()),
+        }
     }
 
-    pub fn save(&self) -> Result<()> {
-        let json = serde_json::to_string_pretty(&self)?;
-        fs::write(&*FILE_PATH, json)?;
-        Ok(())
+    /// Hands unsaved changes to the writer thread, only the tasks that changed are sent.
+    pub fn save(&mut self) -> Result<()> {
+        // Don't overwrite changes made outside of nest, merge them first
+        self.reload_if_changed_externally()?;
+
+        let tasks_changed = self.tasks_changed_at.take().is_some();
+        let view_changed = self.view.take_changed();
+        let journal = std::mem::take(&mut self.journal);
+
+        match &self.writer {
+            Some(writer) if tasks_changed || view_changed => {
+                let tasks = tasks_changed.then(|| match std::mem::take(&mut self.saved_tasks_reloaded) {
+                    true => {
+                        self.saved_tasks = self.tasks.clone();
+                        TasksUpdate::Replaced(self.tasks.clone())
+                    }
+                    false => {
+                        let changes = TaskChanges::between(&self.saved_tasks, &self.tasks);
+                        changes.apply(&mut self.saved_tasks);
+                        TasksUpdate::Changed(changes)
+                    }
+                });
+                writer.save(self.view.clone(), tasks, journal)
+            }
+            _ => Ok(()),
+        }
     }
 
-    pub fn load_state() -> Result<AppStorage> {
-        let json_str = fs::read_to_string(&*FILE_PATH)?;
-        Ok(serde_json::from_str::<AppStorage>(&json_str)?)
+    /// Saves unsaved changes and waits until they're written to the disk.
+    pub fn flush(&mut self) -> Result<()> {
+        self.save()?;
+        match &self.writer {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Loads the state file, starting with an empty tree if it doesn't exist yet.
+    ///
+    /// Fails with a `CorruptStateFile` if the file is corrupt.
+    pub fn load_state(config: StorageConfig) -> Result<AppStorage> {
+        Self::load_from(&config.file_path.clone(), config)
+    }
+
+    /// Loads the state from `source`, e.g. a backup, to be saved later to the configured file.
+    ///
+    /// If another instance has the state file locked, the storage is loaded in read-only mode.
+    pub fn load_from(source: &Path, config: StorageConfig) -> Result<AppStorage> {
+        let file_path = config.file_path.clone();
+        if let Some(parent_dir) = file_path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(parent_dir)?;
+        }
+
+        // Lock before reading, so no other instance writes between reading and locking
+        let lock_attempt = match config.read_only {
+            true => None,
+            false => Some(lock::lock_state_file(&file_path)?),
+        };
+
+        let disk_stamp = FileStamp::read(&file_path)?;
+        let loaded = match source.try_exists()? {
+            true => config.backend.open(source).load()?,
+            false => None,
+        };
+        let (state_file, version) = loaded.unwrap_or_else(|| (StateFile::default(), schema::CURRENT_VERSION));
+
+        let mut storage = Self::from_state_file(state_file);
+        storage.disk_stamp = Arc::new(Mutex::new(disk_stamp));
+
+        if version < schema::CURRENT_VERSION {
+            let mut message = format!("Upgraded the state file from version {version} of the format");
+            // Keep the original around, as older builds of nest can't open the upgraded one
+            if matches!(lock_attempt, Some(LockAttempt::Acquired(_))) {
+                let backup = backup::create_migration_backup(source, &file_path, version)?;
+                message.push_str(&format!(", the original is kept in {}", path::file_name(&backup)));
+            }
+            storage.sync_message = Some(message);
+            storage.mark_tasks_changed();
+        }
+
+        match lock_attempt {
+            Some(LockAttempt::Acquired(lock)) => {
+                let backend = config.backend.open(&file_path);
+                let is_saved = source == file_path && version == schema::CURRENT_VERSION;
+                let writer_state = storage.to_state_file();
+                storage.writer = Some(StorageWriter::spawn(
+                    config.clone(),
+                    backend,
+                    writer_state,
+                    is_saved,
+                    Arc::clone(&storage.disk_stamp),
+                ));
+                storage.lock = Some(lock);
+            }
+            Some(LockAttempt::HeldBy(pid)) => {
+                storage.read_only_reason =
+                    Some(format!("nest is already open in {}", LockAttempt::describe_holder(pid)));
+            }
+            None => storage.read_only_reason = Some("opened with --read-only".to_owned()),
+        }
+
+        // The watcher only makes saving safer, so go on without it if the OS won't provide one
+        storage.watcher = FileWatcher::new(&file_path).ok();
+
+        if source != file_path {
+            storage.mark_tasks_changed();
+        }
+        storage.file_path = Some(file_path);
+        storage.backend = config.backend;
+        let orphans = storage.adopt_orphans();
+        storage.report_orphans(orphans);
+        Ok(storage)
+    }
+
+    pub fn read_only_reason(&self) -> Option<&str> {
+        self.read_only_reason.as_deref()
+    }
+
+    /// Where the undo history is kept between sessions, `None` when this instance can't save, so
+    /// it doesn't overwrite the history of the one that can.
+    pub fn history_file_path(&self) -> Option<PathBuf> {
+        self.writer.as_ref()?;
+        let file_path = self.file_path.as_ref()?;
+        let mut history_name = file_path.file_name().unwrap_or_default().to_os_string();
+        history_name.push(".history.json");
+        Some(file_path.with_file_name(history_name))
+    }
+
+    fn from_state_file(state_file: StateFile) -> Self {
+        let StateFile {
+            version: _,
+            view,
+            tasks,
+        } = state_file;
+
+        let tasks = Arc::unwrap_or_clone(tasks);
+        AppStorage {
+            view,
+            tasks: tasks.clone(),
+            saved_tasks: tasks,
+            ..AppStorage::default()
+        }
+    }
+
+    /// A storage for redoing operations on, that isn't saved anywhere.
+    fn for_replay(view: ViewStorage, tasks: IndexMap<TaskId, Task>) -> Self {
+        AppStorage {
+            view,
+            tasks,
+            ..AppStorage::default()
+        }
+    }
+
+    fn to_state_file(&self) -> StateFile {
+        StateFile {
+            version: schema::CURRENT_VERSION,
+            view: self.view.clone(),
+            tasks: Arc::new(self.saved_tasks.clone()),
+        }
     }
 }
@@ -0,0 +1,130 @@
+//! Where the state file lives.
+
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{
+    Result,
+    eyre::{bail, eyre},
+};
+
+use super::BackendKind;
+
+const DEFAULT_NEST_NAME: &str = "default";
+
+/// Where nest kept its state before supporting multiple nests, still used if present.
+const LEGACY_FILE_NAME: &str = "nest_state.json";
+
+/// Picks the state file, in order: the `--file` flag, the nest named by `--name`, `$NEST_FILE`,
+/// and then the default nest.
+///
+/// Named nests get the extension of `backend`. If it's not picked, they use the file of the nest
+/// that already exists, or `.json` for a new nest.
+pub fn resolve_file_path(file: Option<PathBuf>, name: Option<&str>, backend: Option<BackendKind>) -> Result<PathBuf> {
+    if let Some(file) = file {
+        return Ok(file);
+    }
+
+    if let Some(name) = name {
+        return named_nest_path(name, backend);
+    }
+
+    if let Some(file) = env::var_os("NEST_FILE").filter(|file| !file.is_empty()) {
+        return Ok(PathBuf::from(file));
+    }
+
+    let default_path = named_nest_path(DEFAULT_NEST_NAME, backend)?;
+    if !default_path.try_exists()? && backend.unwrap_or_default() == BackendKind::Json {
+        let legacy_path = env::var_os("HOME").map(|home| Path::new(&home).join(LEGACY_FILE_NAME));
+        if let Some(legacy_path) = legacy_path.filter(|path| path.is_file()) {
+            return Ok(legacy_path);
+        }
+    }
+
+    Ok(default_path)
+}
+
+fn named_nest_path(name: &str, backend: Option<BackendKind>) -> Result<PathBuf> {
+    if name.is_empty() || name.starts_with('.') || name.contains(std::path::is_separator) {
+        bail!("invalid nest name `{name}`, it can't be empty, start with a dot or contain slashes");
+    }
+
+    nest_file_in(&data_dir()?, name, backend)
+}
+
+/// The file of the nest `name` in `dir`, failing if `backend` isn't picked and the nest has files
+/// for several backends.
+fn nest_file_in(dir: &Path, name: &str, backend: Option<BackendKind>) -> Result<PathBuf> {
+    let path_for = |backend: BackendKind| dir.join(format!("{name}.{}", backend.extension()));
+    if let Some(backend) = backend {
+        return Ok(path_for(backend));
+    }
+
+    let mut existing = vec![];
+    for path in BackendKind::ALL.map(path_for) {
+        if path.try_exists()? {
+            existing.push(path);
+        }
+    }
+
+    match existing.as_slice() {
+        [] => Ok(path_for(BackendKind::default())),
+        [path] => Ok(path.clone()),
+        paths => {
+            let names: Vec<_> = paths.iter().map(|path| file_name(path)).collect();
+            bail!(
+                "the nest `{name}` has several state files ({}), pick one with `--backend` or `--file`",
+                names.join(", ")
+            )
+        }
+    }
+}
+
+/// `$XDG_DATA_HOME/nest`, falling back to `$HOME/.local/share/nest`.
+fn data_dir() -> Result<PathBuf> {
+    let xdg_data_home = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute());
+
+    let data_home = match xdg_data_home {
+        Some(path) => path,
+        None => {
+            let home = env::var_os("HOME")
+                .filter(|home| !home.is_empty())
+                .ok_or_else(|| eyre!("neither $XDG_DATA_HOME nor $HOME are set, use `--file` to pick a state file"))?;
+            Path::new(&home).join(".local/share")
+        }
+    };
+
+    Ok(data_home.join("nest"))
+}
+
+/// Just the file name of `path`, for showing to the user.
+pub fn file_name(path: &Path) -> String {
+    path.file_name().unwrap_or_default().to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_existing_file_of_a_nest() {
+        let dir = tempfile::tempdir().unwrap();
+        let nest_file = |backend| nest_file_in(dir.path(), "work", backend).map(|path| file_name(&path));
+
+        assert_eq!(nest_file(None).unwrap(), "work.json");
+        assert_eq!(nest_file(Some(BackendKind::Journal)).unwrap(), "work.jsonl");
+
+        std::fs::write(dir.path().join("work.db"), "").unwrap();
+        assert_eq!(nest_file(None).unwrap(), "work.db");
+        assert_eq!(nest_file(Some(BackendKind::Json)).unwrap(), "work.json");
+
+        std::fs::write(dir.path().join("work.jsonl"), "").unwrap();
+        let error = nest_file(None).unwrap_err().to_string();
+        assert!(error.contains("work.db, work.jsonl"), "{error}");
+        assert_eq!(nest_file(Some(BackendKind::Sqlite)).unwrap(), "work.db");
+    }
+}
@@ -0,0 +1,324 @@
+//! Versions of the state file layout and the migrations between them.
+//!
+//! Files are upgraded step by step on load, one migration per version, working on the raw JSON so
+//! the current types never need to understand old layouts.
+
+use color_eyre::{Result, eyre::bail};
+use serde::de::Error as _;
+use serde_json::{Map, Value};
+
+use super::{CorruptStateFile, StateFile};
+use crate::entities::TaskId;
+
+/// The version written by this build, bump it and add a migration on every change to the layout.
+pub const CURRENT_VERSION: u64 = 6;
+
+/// Upgrades a document by one version, fails with a `serde_json::Error` on unexpected contents.
+type Migration = fn(&mut Map<String, Value>) -> serde_json::Result<()>;
+
+/// `MIGRATIONS[n]` upgrades a document from version `n` to `n + 1`.
+const MIGRATIONS: [Migration; CURRENT_VERSION as usize] = [
+    unversioned_to_v1,
+    integer_ids_to_ulids,
+    add_timestamps,
+    add_notes,
+    done_to_status,
+    add_dates,
+];
+
+/// Parses a state file of any version up to `CURRENT_VERSION`, returning the version it was in.
+///
+/// Fails with a `CorruptStateFile` if it's corrupt.
+pub fn parse_state_file(json: &[u8]) -> Result<(StateFile, u64)> {
+    migrate_document(serde_json::from_slice(json).map_err(CorruptStateFile::new)?)
+}
+
+/// Like `parse_state_file`, for documents put together by a backend that doesn't store JSON files.
+pub fn migrate_document(mut document: Value) -> Result<(StateFile, u64)> {
+    let Some(object) = document.as_object_mut() else {
+        return Err(CorruptStateFile::new(serde_json::Error::custom("the state file is not a JSON object")).into());
+    };
+
+    let version = match object.get("version") {
+        None => 0,
+        Some(version) => version.as_u64().ok_or_else(|| {
+            CorruptStateFile::new(serde_json::Error::custom(format!(
+                "invalid state file version {version}"
+            )))
+        })?,
+    };
+    if version > CURRENT_VERSION {
+        bail!(
+            "the state file is in version {version} of the format, but this build of nest only understands up to \
+             version {CURRENT_VERSION}, update nest to open it"
+        );
+    }
+
+    for (migration, next_version) in MIGRATIONS[version as usize..].iter().zip(version + 1..) {
+        migration(object).map_err(CorruptStateFile::new)?;
+        object.insert("version".to_owned(), next_version.into());
+    }
+
+    let state_file = serde_json::from_value(document).map_err(CorruptStateFile::new)?;
+    Ok((state_file, version))
+}
+
+/// Files written before the version field existed, the layout is otherwise unchanged.
+fn unversioned_to_v1(_document: &mut Map<String, Value>) -> serde_json::Result<()> {
+    Ok(())
+}
+
+/// Task ids went from integers to ULIDs, rewrites the ids and every reference to them.
+fn integer_ids_to_ulids(document: &mut Map<String, Value>) -> serde_json::Result<()> {
+    fn convert_id(id: &mut Value) -> serde_json::Result<()> {
+        match id {
+            Value::Number(number) => {
+                let legacy_id = number
+                    .as_u64()
+                    .ok_or_else(|| serde_json::Error::custom(format!("invalid task id {number}")))?;
+                *id = TaskId::from_legacy(legacy_id).to_string().into();
+                Ok(())
+            }
+            Value::Null => Ok(()),
+            _ => Err(serde_json::Error::custom(format!("invalid task id {id}"))),
+        }
+    }
+    // `ParentTask::Id` is `{"Id": <id>}`, `ParentTask::Root` is `"Root"`
+    fn convert_parent(parent: &mut Value) -> serde_json::Result<()> {
+        match parent.get_mut("Id") {
+            Some(id) => convert_id(id),
+            None => Ok(()),
+        }
+    }
+    fn array_mut(value: Option<&mut Value>) -> impl Iterator<Item = &mut Value> {
+        value.and_then(Value::as_array_mut).into_iter().flatten()
+    }
+
+    if let Some(Value::Object(tasks)) = document.get_mut("tasks") {
+        for (key, mut task) in std::mem::take(tasks) {
+            if let Some(id) = task.get_mut("id") {
+                convert_id(id)?;
+            }
+            if let Some(parent) = task.get_mut("parent") {
+                convert_parent(parent)?;
+            }
+            for child_id in array_mut(task.get_mut("children")) {
+                convert_id(child_id)?;
+            }
+
+            let legacy_id = key.parse().map_err(serde_json::Error::custom)?;
+            tasks.insert(TaskId::from_legacy(legacy_id).to_string(), task);
+        }
+    }
+
+    if let Some(view) = document.get_mut("view") {
+        if let Some(opened_task) = view.get_mut("opened_task") {
+            convert_parent(opened_task)?;
+        }
+        for position in array_mut(view.get_mut("positions_in_opened_task")) {
+            if let Some(parent) = position.get_mut(0) {
+                convert_parent(parent)?;
+            }
+        }
+        for jump in array_mut(view.get_mut("jumps")) {
+            if let Some(opened_task) = jump.get_mut("opened_task") {
+                convert_parent(opened_task)?;
+            }
+            if let Some(selected_task) = jump.get_mut("selected_task") {
+                convert_id(selected_task)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Tasks got `created_at`, `updated_at` and `completed_at`, missing ones are read as unknown.
+///
+/// Nothing to change, the bump keeps older builds from opening files with timestamps and dropping
+/// them on save.
+fn add_timestamps(_document: &mut Map<String, Value>) -> serde_json::Result<()> {
+    Ok(())
+}
+
+/// Tasks got `notes`, missing ones are read as empty, likewise kept from older builds.
+fn add_notes(_document: &mut Map<String, Value>) -> serde_json::Result<()> {
+    Ok(())
+}
+
+/// The `done` flag of tasks became a `status`, done tasks are done and the others still to do.
+fn done_to_status(document: &mut Map<String, Value>) -> serde_json::Result<()> {
+    let Some(Value::Object(tasks)) = document.get_mut("tasks") else {
+        return Ok(());
+    };
+
+    // Tasks that already have a status are left as they are
+    for task in tasks
+        .values_mut()
+        .filter_map(Value::as_object_mut)
+        .filter(|task| !task.contains_key("status"))
+    {
+        let status = match task.remove("done") {
+            Some(Value::Bool(true)) => "done",
+            Some(Value::Bool(false)) | None => "todo",
+            Some(done) => return Err(serde_json::Error::custom(format!("invalid done flag {done}"))),
+        };
+        task.insert("status".to_owned(), status.into());
+    }
+
+    Ok(())
+}
+
+/// Tasks got `due` and `scheduled` dates, missing ones are read as unset, likewise kept from older
+/// builds.
+fn add_dates(_document: &mut Map<String, Value>) -> serde_json::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::entities::{ParentTask, TaskStatus};
+
+    /// Ids of the tasks in the fixtures from version 2 on, earlier ones use 1 to 4.
+    const ULIDS: [&str; 4] = [
+        "01JAB3Q5X00000000000000001",
+        "01JAB3Q5X00000000000000002",
+        "01JAB3Q5X00000000000000003",
+        "01JAB3Q5X00000000000000004",
+    ];
+
+    pub(crate) fn legacy_ids() -> [TaskId; 4] {
+        [1, 2, 3, 4].map(TaskId::from_legacy)
+    }
+
+    pub(crate) fn ulids() -> [TaskId; 4] {
+        ULIDS.map(|id| serde_json::from_value(id.into()).unwrap())
+    }
+
+    /// Checks the tree every fixture holds: "Work" with the done "Write report" and "Call Bob"
+    /// under it, then "Home", with "Work" opened.
+    pub(crate) fn assert_fixture_tree(state: &StateFile, [work, report, call, home]: [TaskId; 4]) {
+        assert_eq!(state.version, CURRENT_VERSION);
+
+        let ids: Vec<_> = state.tasks.keys().copied().collect();
+        assert_eq!(ids, [work, report, call, home]);
+        for (id, task) in state.tasks.iter() {
+            assert_eq!(task.id, *id);
+        }
+
+        let task = |id| &state.tasks[&id];
+        assert_eq!(task(work).parent, ParentTask::Root);
+        assert_eq!(task(work).children, [report, call]);
+        assert_eq!(task(report).parent, ParentTask::Id(work));
+        assert_eq!(task(call).parent, ParentTask::Id(work));
+        assert_eq!(task(home).parent, ParentTask::Root);
+
+        assert_eq!(task(work).status, TaskStatus::Todo);
+        assert_eq!(task(report).status, TaskStatus::Done);
+        assert_eq!(task(call).status, TaskStatus::Todo);
+        assert_eq!(task(home).status, TaskStatus::Todo);
+
+        assert_eq!(state.view.opened_task, ParentTask::Id(work));
+        assert!(state.view.positions_in_opened_task.contains(&(ParentTask::Id(work), 1)));
+    }
+
+    fn parse_fixture(json: &str, expected_version: u64) -> StateFile {
+        let (state, version) = parse_state_file(json.as_bytes()).unwrap();
+        assert_eq!(version, expected_version);
+        state
+    }
+
+    #[test]
+    fn migrates_unversioned_files() {
+        let state = parse_fixture(include_str!("../../tests/fixtures/state_v0.json"), 0);
+        assert_fixture_tree(&state, legacy_ids());
+        assert!(state.view.jumps.is_empty());
+    }
+
+    #[test]
+    fn migrates_v1_integer_ids_in_the_view() {
+        let state = parse_fixture(include_str!("../../tests/fixtures/state_v1.json"), 1);
+        let [work, _, call, _] = legacy_ids();
+        assert_fixture_tree(&state, legacy_ids());
+        assert_eq!(state.view.jumps[0].opened_task, ParentTask::Root);
+        assert_eq!(state.view.jumps[0].selected_task, Some(work));
+        assert_eq!(state.view.jumps[1].opened_task, ParentTask::Id(work));
+        assert_eq!(state.view.jumps[1].selected_task, Some(call));
+    }
+
+    #[test]
+    fn migrates_v2_without_timestamps() {
+        let state = parse_fixture(include_str!("../../tests/fixtures/state_v2.json"), 2);
+        assert_fixture_tree(&state, ulids());
+        assert!(state.tasks.values().all(|task| task.created_at.is_none()));
+    }
+
+    #[test]
+    fn migrates_v3_timestamps() {
+        let state = parse_fixture(include_str!("../../tests/fixtures/state_v3.json"), 3);
+        let [_, report, call, _] = ulids();
+        assert_fixture_tree(&state, ulids());
+        assert!(state.tasks[&report].completed_at.is_some());
+        assert!(state.tasks[&call].completed_at.is_none());
+        assert!(state.tasks.values().all(|task| task.created_at.is_some()));
+    }
+
+    #[test]
+    fn migrates_v4_notes() {
+        let state = parse_fixture(include_str!("../../tests/fixtures/state_v4.json"), 4);
+        let [_, _, call, _] = ulids();
+        assert_fixture_tree(&state, ulids());
+        assert_eq!(state.tasks[&call].notes, "Ask about the budget\nand the deadline");
+    }
+
+    #[test]
+    fn migrates_v5_statuses() {
+        let state = parse_fixture(include_str!("../../tests/fixtures/state_v5.json"), 5);
+        assert_fixture_tree(&state, ulids());
+        assert!(state.tasks.values().all(|task| task.due.is_none()));
+    }
+
+    #[test]
+    fn reads_the_current_version() {
+        let state = parse_fixture(include_str!("../../tests/fixtures/state_v6.json"), 6);
+        let [_, _, call, _] = ulids();
+        assert_eq!(CURRENT_VERSION, 6, "add a fixture for the new version");
+        assert_fixture_tree(&state, ulids());
+        assert_eq!(state.tasks[&call].due, "2026-11-02".parse().ok());
+    }
+
+    #[test]
+    fn turns_done_flags_into_statuses() {
+        let mut document = serde_json::json!({
+            "tasks": {
+                "1": {"done": true},
+                "2": {"done": false},
+                "3": {},
+                "4": {"done": true, "status": "cancelled"},
+            }
+        });
+        done_to_status(document.as_object_mut().unwrap()).unwrap();
+        let statuses: Vec<_> = document["tasks"].as_object().unwrap().values().cloned().collect();
+        assert_eq!(
+            statuses,
+            [
+                serde_json::json!({"status": "done"}),
+                serde_json::json!({"status": "todo"}),
+                serde_json::json!({"status": "todo"}),
+                serde_json::json!({"done": true, "status": "cancelled"}),
+            ]
+        );
+
+        let mut document = serde_json::json!({"tasks": {"1": {"done": "yes"}}});
+        let error = done_to_status(document.as_object_mut().unwrap()).unwrap_err();
+        assert!(error.to_string().contains("invalid done flag"));
+    }
+
+    #[test]
+    fn refuses_newer_versions() {
+        let json = format!(r#"{{"version": {}, "view": {{}}, "tasks": {{}}}}"#, CURRENT_VERSION + 1);
+        let error = parse_state_file(json.as_bytes()).unwrap_err();
+        assert!(error.to_string().contains("update nest"));
+    }
+}
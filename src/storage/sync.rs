@@ -0,0 +1,123 @@
+//! Picks up changes made to the state file outside of this instance, e.g. by a sync tool or by hand.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, PoisonError},
+};
+
+use color_eyre::Result;
+use indexmap::IndexMap;
+
+use super::{AppStorage, journal::Operation, merge, watcher::FileStamp};
+use crate::entities::{ParentTask, Task, TaskId};
+
+impl AppStorage {
+    /// Reloads the state file if it changed since this instance last read or wrote it, merging
+    /// in unsaved changes.
+    pub fn reload_if_changed_externally(&mut self) -> Result<()> {
+        let Some(file_path) = self.file_path.clone() else {
+            return Ok(());
+        };
+
+        let new_stamp = {
+            // The writer holds the lock while writing, so its writes are never mistaken for external ones
+            let mut disk_stamp = self.disk_stamp.lock().unwrap_or_else(PoisonError::into_inner);
+            let new_stamp = FileStamp::read(&file_path)?;
+            if *disk_stamp == new_stamp {
+                return Ok(());
+            }
+            *disk_stamp = new_stamp;
+            new_stamp
+        };
+
+        if new_stamp.is_none() {
+            // Write it back on the next save
+            self.mark_tasks_changed();
+            self.sync_message = Some("The state file was deleted outside of nest, it will be saved again".to_owned());
+            return Ok(());
+        }
+
+        let state_file = self.backend.open(&file_path).load().ok().flatten();
+        let theirs = match state_file {
+            Some((state_file, _)) => state_file.tasks,
+            // Most likely caught in the middle of being written, the next change will be picked up
+            None => {
+                self.sync_message = Some("The state file was changed outside of nest, but couldn't be read".to_owned());
+                return Ok(());
+            }
+        };
+
+        // Only the view changed, or the file was just touched
+        if theirs.iter().eq(self.saved_tasks.iter()) {
+            return Ok(());
+        }
+
+        let selected_task = self.get_selected_task_id();
+        let merged = merge::merge_tasks(&self.saved_tasks, &self.tasks, &theirs);
+        let conflict_titles = merged
+            .conflicts
+            .iter()
+            .filter_map(|id| merged.tasks.get(id))
+            .map(|task| format!("\"{}\"", task.title))
+            .collect::<Vec<_>>();
+
+        self.saved_tasks = Arc::unwrap_or_clone(theirs);
+        self.saved_tasks_reloaded = true;
+        self.tasks = merged.tasks;
+        repair_tree(&mut self.tasks);
+        let orphans = self.adopt_orphans();
+        self.record(Operation::Replace);
+        // The merged tasks differ from the file whenever there were unsaved changes, saving
+        // them all is simpler than finding out
+        self.mark_tasks_changed();
+
+        let opened_task = match self.view.opened_task {
+            ParentTask::Id(id) if !self.tasks.contains_key(&id) => ParentTask::Root,
+            opened_task => opened_task,
+        };
+        self.view.set_opened_task(opened_task);
+        let selected_position = selected_task.and_then(|id| {
+            let tasks = self.find_children(opened_task);
+            tasks.iter().position(|task| task.id == id)
+        });
+        self.set_selected_position(selected_position.unwrap_or(0));
+
+        self.sync_message = Some(match conflict_titles.is_empty() {
+            true => "Reloaded changes made outside of nest".to_owned(),
+            false => format!(
+                "Reloaded changes made outside of nest, kept the local version of {}",
+                conflict_titles.join(", ")
+            ),
+        });
+        self.report_orphans(orphans);
+        Ok(())
+    }
+
+    /// What happened in the last reload, if it wasn't shown yet.
+    pub fn take_sync_message(&mut self) -> Option<String> {
+        self.sync_message.take()
+    }
+
+    fn get_selected_task_id(&self) -> Option<TaskId> {
+        let position = self.get_selected_position()?;
+        let tasks = self.find_children(self.view.opened_task);
+        tasks.get(position).map(|task| task.id)
+    }
+}
+
+/// Points each task to the first parent listing it, for tasks moved to different places on each
+/// side of a merge, `adopt_orphans` then drops the other listings.
+fn repair_tree(tasks: &mut IndexMap<TaskId, Task>) {
+    let mut listed_by = HashMap::new();
+    for task in tasks.values() {
+        for child_id in &task.children {
+            listed_by.entry(*child_id).or_insert(task.id);
+        }
+    }
+
+    for task in tasks.values_mut() {
+        if let Some(parent_id) = listed_by.get(&task.id) {
+            task.parent = ParentTask::Id(*parent_id);
+        }
+    }
+}
@@ -1,11 +1,31 @@
+use std::time::Instant;
+
 use serde::{Deserialize, Serialize};
 
-use crate::entities::ParentTask;
+use crate::entities::{ParentTask, TaskId};
+
+/// Maximum amount of entries kept in the jump list.
+const MAX_JUMPS: usize = 100;
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct ViewStorage {
     pub opened_task: ParentTask,
     pub positions_in_opened_task: Vec<(ParentTask, usize)>,
+    /// Locations visited, from oldest to newest, at most one per opened task.
+    #[serde(default)]
+    pub jumps: Vec<Jump>,
+    /// Index of the current location in `jumps`.
+    #[serde(default)]
+    pub jump_position: usize,
+    /// When the view was last changed, if it wasn't saved since.
+    #[serde(skip)]
+    changed_at: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Jump {
+    pub opened_task: ParentTask,
+    pub selected_task: Option<TaskId>,
 }
 
 impl ViewStorage {
@@ -14,7 +34,23 @@ impl ViewStorage {
     }
 
     pub fn set_opened_task(&mut self, opened_task: ParentTask) {
-        self.opened_task = opened_task;
+        if self.opened_task != opened_task {
+            self.opened_task = opened_task;
+            self.mark_changed();
+        }
+    }
+
+    pub fn changed_at(&self) -> Option<Instant> {
+        self.changed_at
+    }
+
+    /// Clears the unsaved changes mark, returning whether it was set.
+    pub fn take_changed(&mut self) -> bool {
+        self.changed_at.take().is_some()
+    }
+
+    fn mark_changed(&mut self) {
+        self.changed_at = Some(Instant::now());
     }
 
     pub fn get_selected_position(&self) -> Option<usize> {
@@ -25,7 +61,36 @@ impl ViewStorage {
     }
 
     pub fn set_selected_position(&mut self, index: usize) {
+        if self.get_selected_position() == Some(index) {
+            return;
+        }
         self.positions_in_opened_task.retain(|p| p.0 != self.opened_task);
         self.positions_in_opened_task.push((self.opened_task, index));
+        self.mark_changed();
+    }
+
+    pub fn set_jump_position(&mut self, jump_position: usize) {
+        self.jump_position = jump_position;
+        self.mark_changed();
+    }
+
+    /// Records the current location, updating the current entry if it's still in the same opened task.
+    ///
+    /// Recording a new location drops the entries ahead of the current one, like browser history.
+    pub fn record_jump(&mut self, jump: Jump) {
+        match self.jumps.get_mut(self.jump_position) {
+            Some(current) if *current == jump => return,
+            Some(current) if current.opened_task == jump.opened_task => *current = jump,
+            _ => {
+                self.jumps.truncate(self.jump_position + 1);
+                self.jumps.retain(|entry| entry.opened_task != jump.opened_task);
+                self.jumps.push(jump);
+
+                let overflow = self.jumps.len().saturating_sub(MAX_JUMPS);
+                self.jumps.drain(..overflow);
+                self.jump_position = self.jumps.len() - 1;
+            }
+        }
+        self.mark_changed();
     }
 }
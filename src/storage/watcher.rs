@@ -0,0 +1,77 @@
+//! Notices when the state file is changed by something other than this instance.
+
+use std::{
+    ffi::OsString,
+    io,
+    path::Path,
+    sync::mpsc::{self, Receiver},
+    time::SystemTime,
+};
+
+use color_eyre::Result;
+use fs_err as fs;
+use notify::{RecursiveMode, Watcher};
+
+/// Identifies a version of a file without reading it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    modified: SystemTime,
+    len: u64,
+}
+
+impl FileStamp {
+    /// `None` if the file doesn't exist.
+    pub fn read(path: &Path) -> Result<Option<Self>> {
+        match fs::metadata(path) {
+            Ok(metadata) => Ok(Some(FileStamp {
+                modified: metadata.modified()?,
+                len: metadata.len(),
+            })),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+}
+
+/// Watches the directory of the state file (inotify on Linux), because saving replaces the file
+/// instead of modifying it.
+pub struct FileWatcher {
+    _watcher: notify::RecommendedWatcher,
+    events: Receiver<()>,
+}
+
+impl std::fmt::Debug for FileWatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FileWatcher").finish_non_exhaustive()
+    }
+}
+
+impl FileWatcher {
+    pub fn new(file_path: &Path) -> Result<Self> {
+        let dir = match file_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let file_name = file_path.file_name().map(OsString::from);
+
+        let (sender, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            let touches_file =
+                event.is_ok_and(|event| event.paths.iter().any(|path| path.file_name() == file_name.as_deref()));
+            if touches_file {
+                _ = sender.send(());
+            }
+        })?;
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+        Ok(Self {
+            _watcher: watcher,
+            events,
+        })
+    }
+
+    /// Whether the file was touched since the last call, including by this instance.
+    pub fn has_changed(&self) -> bool {
+        self.events.try_iter().count() > 0
+    }
+}
@@ -0,0 +1,231 @@
+//! Background thread that writes the state file, so saving never blocks the UI.
+
+use std::{
+    collections::HashSet,
+    sync::{
+        Arc, Mutex, PoisonError,
+        mpsc::{self, Receiver, Sender},
+    },
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+use color_eyre::{Report, Result};
+use indexmap::IndexMap;
+
+use super::{
+    BACKUP_INTERVAL, StateFile, StorageConfig, backend::StorageBackend, backup, journal::JournalEntry,
+    view::ViewStorage, watcher::FileStamp,
+};
+use crate::{
+    entities::{Task, TaskId},
+    history::TaskChanges,
+};
+
+/// How the tasks changed since they were last sent to the writer.
+pub enum TasksUpdate {
+    /// Only the tasks that differ, applied to the ones sent before.
+    Changed(TaskChanges),
+    /// All of them, when the ones sent before aren't what they changed from, e.g. after reloading.
+    Replaced(IndexMap<TaskId, Task>),
+}
+
+enum WriterMessage {
+    Save {
+        view: ViewStorage,
+        /// `None` if the tasks didn't change since the last save.
+        tasks: Option<TasksUpdate>,
+        /// The operations that changed the tasks.
+        journal: Vec<JournalEntry>,
+    },
+    Flush(Sender<()>),
+}
+
+#[derive(Debug)]
+pub struct StorageWriter {
+    sender: Option<Sender<WriterMessage>>,
+    thread: Option<JoinHandle<()>>,
+    /// Last error hit by the thread, reported back on the next save or flush.
+    error: Arc<Mutex<Option<Report>>>,
+}
+
+impl StorageWriter {
+    /// Spawns the thread writing through `backend`, `state` is what's currently loaded and
+    /// `is_saved` whether it matches what `backend` has saved, otherwise the first save writes
+    /// everything.
+    ///
+    /// `disk_stamp` is updated after every write, so our own writes aren't mistaken for external changes.
+    pub fn spawn(
+        config: StorageConfig,
+        backend: Box<dyn StorageBackend>,
+        state: StateFile,
+        is_saved: bool,
+        disk_stamp: Arc<Mutex<Option<FileStamp>>>,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let error = Arc::new(Mutex::new(None));
+
+        let thread_error = Arc::clone(&error);
+        let thread = thread::Builder::new()
+            .name("nest-writer".to_owned())
+            .spawn(move || {
+                let written_stamp = *disk_stamp.lock().unwrap_or_else(PoisonError::into_inner);
+                let writer = Writer {
+                    config,
+                    backend,
+                    is_saved,
+                    written_stamp,
+                    last_backup_at: None,
+                    disk_stamp,
+                };
+                writer.write_loop(state, receiver, thread_error);
+            })
+            .expect("failed to spawn the writer thread");
+
+        Self {
+            sender: Some(sender),
+            thread: Some(thread),
+            error,
+        }
+    }
+
+    pub fn save(&self, view: ViewStorage, tasks: Option<TasksUpdate>, journal: Vec<JournalEntry>) -> Result<()> {
+        self.send(WriterMessage::Save { view, tasks, journal });
+        self.take_error()
+    }
+
+    /// Waits until everything sent so far is written.
+    pub fn flush(&self) -> Result<()> {
+        let (ack_sender, ack_receiver) = mpsc::channel();
+        self.send(WriterMessage::Flush(ack_sender));
+        _ = ack_receiver.recv();
+        self.take_error()
+    }
+
+    fn send(&self, message: WriterMessage) {
+        if let Some(sender) = &self.sender {
+            // Only fails if the thread is gone, and then the error was already reported
+            _ = sender.send(message);
+        }
+    }
+
+    fn take_error(&self) -> Result<()> {
+        match self.error.lock().unwrap_or_else(PoisonError::into_inner).take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for StorageWriter {
+    fn drop(&mut self) {
+        // Closing the channel stops the thread after it writes what's pending
+        drop(self.sender.take());
+        if let Some(thread) = self.thread.take() {
+            _ = thread.join();
+        }
+    }
+}
+
+/// What the thread owns.
+struct Writer {
+    config: StorageConfig,
+    backend: Box<dyn StorageBackend>,
+    /// Whether the state the thread holds is what's saved, it isn't after a failed write.
+    is_saved: bool,
+    /// Stamp of the file after the last write, if it's different now someone else wrote it.
+    written_stamp: Option<FileStamp>,
+    last_backup_at: Option<Instant>,
+    disk_stamp: Arc<Mutex<Option<FileStamp>>>,
+}
+
+impl Writer {
+    fn write_loop(
+        mut self,
+        mut state: StateFile,
+        receiver: Receiver<WriterMessage>,
+        error: Arc<Mutex<Option<Report>>>,
+    ) {
+        while let Ok(message) = receiver.recv() {
+            let mut has_changes = false;
+            // `None` if the tasks were replaced, so which ones changed isn't known
+            let mut changed_ids = Some(HashSet::new());
+            let mut journal = vec![];
+            let mut flush_acks = vec![];
+
+            // Coalesce everything that piled up while the last write was happening
+            for message in std::iter::once(message).chain(receiver.try_iter()) {
+                match message {
+                    WriterMessage::Save {
+                        view,
+                        tasks,
+                        journal: new_journal,
+                    } => {
+                        state.view = view;
+                        match tasks {
+                            Some(TasksUpdate::Changed(changes)) => {
+                                changes.apply(Arc::make_mut(&mut state.tasks));
+                                if let Some(changed_ids) = &mut changed_ids {
+                                    changed_ids.extend(changes.ids());
+                                }
+                            }
+                            Some(TasksUpdate::Replaced(tasks)) => {
+                                state.tasks = Arc::new(tasks);
+                                changed_ids = None;
+                            }
+                            None => {}
+                        }
+                        journal.extend(new_journal);
+                        has_changes = true;
+                    }
+                    WriterMessage::Flush(ack) => flush_acks.push(ack),
+                }
+            }
+
+            if has_changes && let Err(write_error) = self.write(&state, changed_ids.as_ref(), &journal) {
+                *error.lock().unwrap_or_else(PoisonError::into_inner) = Some(write_error);
+            }
+
+            for ack in flush_acks {
+                _ = ack.send(());
+            }
+        }
+
+        // Nobody is left to report errors to, and what's saved is still readable anyway
+        if self.is_saved {
+            _ = self.backend.close(&state);
+        }
+    }
+
+    /// Saves `state`, backing up the previous contents of the file first if the last backup is old enough.
+    ///
+    /// `changed_ids` are the tasks that changed since the last write, `None` if unknown.
+    fn write(
+        &mut self,
+        state: &StateFile,
+        changed_ids: Option<&HashSet<TaskId>>,
+        journal: &[JournalEntry],
+    ) -> Result<()> {
+        let file_path = &self.config.file_path;
+
+        if self.last_backup_at.is_none_or(|at| at.elapsed() >= BACKUP_INTERVAL) {
+            backup::create_backup(file_path, self.config.backups_to_keep)?;
+            self.last_backup_at = Some(Instant::now());
+        }
+
+        // Hold the stamp while writing, so the UI never sees our write before knowing its stamp
+        let mut disk_stamp = self.disk_stamp.lock().unwrap_or_else(PoisonError::into_inner);
+        if FileStamp::read(file_path)? != self.written_stamp {
+            self.is_saved = false;
+        }
+
+        let changed_ids = changed_ids.filter(|_| self.is_saved);
+        self.is_saved = false;
+        self.backend.save(state, changed_ids, journal)?;
+        self.is_saved = true;
+
+        self.written_stamp = FileStamp::read(file_path)?;
+        *disk_stamp = self.written_stamp;
+        Ok(())
+    }
+}